@@ -1,12 +1,57 @@
-use cargo_metadata::MetadataCommand;
-use radix_trie::Trie;
+use anyhow::Context;
+use cargo_metadata::{Metadata, MetadataCommand, Node, PackageId};
+use globset::GlobBuilder;
+use log::debug;
+use radix_trie::{Trie, TrieCommon};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+/// The section of the manifest a dependency was declared in.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum DependencyKind {
+    Normal,
+    Dev,
+    Build,
+}
+
+impl DependencyKind {
+    pub const ALL: [DependencyKind; 3] = [Self::Normal, Self::Dev, Self::Build];
+}
+
+impl From<cargo_metadata::DependencyKind> for DependencyKind {
+    fn from(kind: cargo_metadata::DependencyKind) -> Self {
+        match kind {
+            cargo_metadata::DependencyKind::Development => Self::Dev,
+            cargo_metadata::DependencyKind::Build => Self::Build,
+            _ => Self::Normal,
+        }
+    }
+}
+
+/// A dependency on another package in the workspace.
+#[derive(Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Dependency {
+    /// Directory of the package depended on
+    pub path: PathBuf,
+    pub kind: DependencyKind,
+}
 
 #[derive(Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Package {
     pub name: String,
     pub manifest: PathBuf,
-    pub dependencies: Vec<PathBuf>,
+    pub dependencies: Vec<Dependency>,
+    /// Whether the package is in `workspace.default-members`, always true if the workspace
+    /// doesn't set any
+    pub default_member: bool,
+    /// Whether the package can be published to a registry
+    pub publish: bool,
 }
 
 fn check_path(root: &Path, path: Option<&Path>) -> bool {
@@ -16,25 +61,279 @@ fn check_path(root: &Path, path: Option<&Path>) -> bool {
     }
 }
 
+/// Name of the metadata cache file, kept in `target/` next to the rest of cargo's output.
+const METADATA_CACHE: &str = "delta_cmd-metadata.json";
+
+/// `cargo metadata` output saved along with what it depends on. It's reused while the lockfile,
+/// every manifest and the directories matched by the member globs are unchanged.
+#[derive(Deserialize, Serialize)]
+struct MetadataCache {
+    /// Workspace the metadata is for, the cache can be shared through `CARGO_TARGET_DIR`
+    root: PathBuf,
+    lock_hash: u64,
+    members_hash: u64,
+    manifests: Vec<(PathBuf, SystemTime)>,
+    metadata: Metadata,
+}
+
+impl MetadataCache {
+    fn path(root: &Path) -> PathBuf {
+        let target = env::var_os("CARGO_TARGET_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| root.join("target"));
+        target.join(METADATA_CACHE)
+    }
+
+    fn load(root: &Path, lock_hash: u64) -> Option<Metadata> {
+        let cache = fs::read(Self::path(root)).ok()?;
+        let cache: Self = serde_json::from_slice(&cache).ok()?;
+        let fresh = cache.root == root
+            && cache.lock_hash == lock_hash
+            && cache.members_hash == members_hash(root)
+            && cache
+                .manifests
+                .iter()
+                .all(|(path, mtime)| modified(path).is_some_and(|x| x == *mtime));
+        fresh.then_some(cache.metadata)
+    }
+
+    fn save(root: &Path, lock_hash: u64, metadata: &Metadata) -> anyhow::Result<()> {
+        let mut manifests = vec![root.join("Cargo.toml")];
+        manifests.extend(
+            metadata
+                .workspace_members
+                .iter()
+                .map(|x| metadata[x].manifest_path.clone().into_std_path_buf()),
+        );
+        let manifests = manifests
+            .into_iter()
+            .filter_map(|x| Some((x.clone(), modified(&x)?)))
+            .collect();
+        let cache = MetadataCache {
+            root: root.to_path_buf(),
+            lock_hash,
+            members_hash: members_hash(root),
+            manifests,
+            metadata: metadata.clone(),
+        };
+        let path = Self::path(root);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_vec(&cache)?)?;
+        Ok(())
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+/// Hashes the `workspace.members` globs along with the manifests they match. A member added under
+/// a glob doesn't change any manifest the cache knows about, so this is what notices it.
+fn members_hash(root: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for pattern in workspace_list(root, "members").unwrap_or_default() {
+        pattern.hash(&mut hasher);
+        expand_member_glob(root, &pattern).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Finds the directories (relative to `root`) with a manifest matching a `workspace.members`
+/// glob. Members without any glob characters are listed in the root manifest so aren't expanded.
+fn expand_member_glob(root: &Path, pattern: &str) -> Vec<PathBuf> {
+    let is_glob = |x: &str| x.contains(['*', '?', '[', '{']);
+    if !is_glob(pattern) {
+        return vec![];
+    }
+    let Ok(glob) = GlobBuilder::new(pattern).literal_separator(true).build() else {
+        return vec![];
+    };
+    let glob = glob.compile_matcher();
+    let prefix = Path::new(pattern)
+        .components()
+        .take_while(|x| !is_glob(&x.as_os_str().to_string_lossy()))
+        .collect::<PathBuf>();
+    // Without `**` nothing deeper than the pattern can match
+    let max_depth = (!pattern.contains("**")).then(|| Path::new(pattern).components().count());
+
+    let mut found = vec![];
+    let mut dirs = vec![prefix];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(root.join(&dir)) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if !entry.file_type().is_ok_and(|x| x.is_dir()) || entry.file_name() == "target" {
+                continue;
+            }
+            let path = dir.join(entry.file_name());
+            if glob.is_match(&path) && root.join(&path).join("Cargo.toml").exists() {
+                found.push(path.clone());
+            }
+            if max_depth.is_none_or(|x| path.components().count() < x) {
+                dirs.push(path);
+            }
+        }
+    }
+    found.sort();
+    found
+}
+
+/// Hash of the lockfile in `root`, `None` if there isn't one.
+fn lock_hash(root: &Path) -> Option<u64> {
+    fs::read(root.join("Cargo.lock")).ok().map(|x| {
+        let mut hasher = DefaultHasher::new();
+        x.hash(&mut hasher);
+        hasher.finish()
+    })
+}
+
+/// Runs `cargo metadata`, saving the output to the cache. Without a lockfile nothing is cached.
+fn fresh_metadata(root: &Path, lock_hash: Option<u64>) -> anyhow::Result<Metadata> {
+    let metadata = MetadataCommand::new()
+        .current_dir(root)
+        .exec()
+        .with_context(|| {
+            format!(
+                "failed to read Cargo workspace metadata at {}",
+                root.display()
+            )
+        })?;
+
+    if let Some(lock_hash) = lock_hash
+        && let Err(e) = MetadataCache::save(root, lock_hash, &metadata)
+    {
+        debug!("Failed to cache cargo metadata: {}", e);
+    }
+    Ok(metadata)
+}
+
+/// Runs `cargo metadata`, reusing the cached output if the lockfile and manifests haven't changed
+/// since it was saved.
+fn workspace_metadata(root: &Path) -> anyhow::Result<Metadata> {
+    let lock_hash = lock_hash(root);
+    if let Some(metadata) = lock_hash.and_then(|x| MetadataCache::load(root, x)) {
+        debug!("Using cached cargo metadata");
+        return Ok(metadata);
+    }
+    fresh_metadata(root, lock_hash)
+}
+
+/// Finds the root directory of the workspace containing `dir`.
+pub fn workspace_root(dir: &Path) -> anyhow::Result<PathBuf> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let output = Command::new(cargo)
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .current_dir(dir)
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "no Cargo workspace found at {}: {}",
+            dir.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let manifest = PathBuf::from(String::from_utf8(output.stdout)?.trim());
+    manifest
+        .parent()
+        .map(Path::to_path_buf)
+        .context("workspace manifest has no parent directory")
+}
+
 pub fn find_packages(root: &Path) -> anyhow::Result<Trie<PathBuf, Package>> {
-    let metadata = MetadataCommand::new().current_dir(root).exec()?;
+    Ok(packages_from_metadata(root, &workspace_metadata(root)?))
+}
+
+/// Like [`find_packages`] but always runs `cargo metadata`, and if the cached output could have
+/// been used checks it finds the same packages. If it doesn't the differences are returned as an
+/// error, the cache is replaced with the fresh output either way.
+pub fn find_packages_checked(root: &Path) -> anyhow::Result<Trie<PathBuf, Package>> {
+    let lock_hash = lock_hash(root);
+    let cached = lock_hash.and_then(|x| MetadataCache::load(root, x));
+    let packages = packages_from_metadata(root, &fresh_metadata(root, lock_hash)?);
+
+    if let Some(cached) = cached {
+        let cached = packages_from_metadata(root, &cached);
+        let drift = package_drift(&cached, &packages);
+        if !drift.is_empty() {
+            anyhow::bail!(
+                "cached cargo metadata doesn't match `cargo metadata`: {}",
+                drift.join(", ")
+            );
+        }
+        debug!("Cached cargo metadata matches `cargo metadata`");
+    }
+    Ok(packages)
+}
 
+/// Describes the packages added, removed or changed going from `old` to `new`.
+fn package_drift(old: &Trie<PathBuf, Package>, new: &Trie<PathBuf, Package>) -> Vec<String> {
+    let old = old.iter().collect::<BTreeMap<_, _>>();
+    let new = new.iter().collect::<BTreeMap<_, _>>();
+    let removed = old
+        .iter()
+        .filter(|(dir, _)| !new.contains_key(*dir))
+        .map(|(_, x)| format!("removed `{}`", x.name));
+    let changed = new.iter().filter_map(|(dir, package)| match old.get(dir) {
+        None => Some(format!("added `{}`", package.name)),
+        Some(old) if old != package => Some(format!("changed `{}`", package.name)),
+        Some(_) => None,
+    });
+    removed.chain(changed).collect()
+}
+
+fn packages_from_metadata(root: &Path, metadata: &Metadata) -> Trie<PathBuf, Package> {
     let mut packages = Trie::new();
 
+    // Workspace members can depend on each other without a path, for example by version with a
+    // `[patch]` pointing at the member. These are looked up in the resolved dependency graph, as
+    // going by name alone would mistake a registry package for a member with the same name
+    let members: HashMap<&PackageId, &Path> = metadata
+        .workspace_members
+        .iter()
+        .filter_map(|x| Some((x, metadata[x].manifest_path.parent()?.as_std_path())))
+        .collect();
+    let resolved: HashMap<&PackageId, &Node> = metadata
+        .resolve
+        .iter()
+        .flat_map(|x| &x.nodes)
+        .map(|x| (&x.id, x))
+        .collect();
+
     for package in &metadata.workspace_members {
         let package = &metadata[package];
 
         let dependencies = package
             .dependencies
             .iter()
-            .filter(|x| check_path(root, x.path.as_ref().map(|x| x.as_std_path())))
-            .map(|x| x.path.clone().unwrap().into_std_path_buf())
+            .filter_map(|x| {
+                let path = match x.path.as_ref() {
+                    Some(path) => path.as_std_path(),
+                    None => resolved
+                        .get(&package.id)?
+                        .deps
+                        .iter()
+                        .filter(|dep| metadata[&dep.pkg].name.as_str() == x.name)
+                        .find_map(|dep| members.get(&dep.pkg))?,
+                };
+                check_path(root, Some(path)).then(|| Dependency {
+                    path: path.to_path_buf(),
+                    kind: x.kind.into(),
+                })
+            })
             .collect();
 
+        let default_member = metadata.workspace_default_members.is_missing()
+            || metadata.workspace_default_members.contains(&package.id);
+
         let pack = Package {
             name: package.name.to_string(),
             manifest: package.manifest_path.clone().into_std_path_buf(),
             dependencies,
+            default_member,
+            publish: package.publish.as_ref().is_none_or(|x| !x.is_empty()),
         };
         packages.insert(
             package
@@ -47,5 +346,70 @@ pub fn find_packages(root: &Path) -> anyhow::Result<Trie<PathBuf, Package>> {
         );
     }
 
-    Ok(packages)
+    packages
+}
+
+/// Reads the `workspace.exclude` list from the manifest in `root`, returning the excluded
+/// directories. Cargo doesn't treat these as part of the workspace even if they're nested inside a
+/// member.
+pub fn workspace_excludes(root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    Ok(workspace_list(root, "exclude")?
+        .into_iter()
+        .map(|x| root.join(x))
+        .collect())
+}
+
+/// Reads a list of strings from the `workspace` table of the manifest in `root`.
+fn workspace_list(root: &Path, key: &str) -> anyhow::Result<Vec<String>> {
+    let manifest = fs::read_to_string(root.join("Cargo.toml"))?;
+    let manifest: toml::Table = toml::from_str(&manifest)?;
+
+    let list = manifest
+        .get("workspace")
+        .and_then(|x| x.get(key))
+        .and_then(|x| x.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|x| x.as_str())
+        .map(ToString::to_string)
+        .collect();
+
+    Ok(list)
+}
+
+/// Gets `package.name` from the contents of a manifest.
+pub fn manifest_package_name(manifest: &str) -> Option<String> {
+    let manifest: toml::Table = toml::from_str(manifest).ok()?;
+    manifest
+        .get("package")?
+        .get("name")?
+        .as_str()
+        .map(ToString::to_string)
+}
+
+/// Whether the manifest at `manifest` declares a dependency on a package called `name`, either
+/// directly or through a `package = "name"` rename. Target specific dependencies are included.
+pub fn declares_dependency(manifest: &Path, name: &str) -> anyhow::Result<bool> {
+    let manifest: toml::Value = toml::from_str(&fs::read_to_string(manifest)?)?;
+
+    let targets = manifest
+        .get("target")
+        .and_then(|x| x.as_table())
+        .into_iter()
+        .flat_map(|x| x.values());
+    let mut dependencies = std::iter::once(&manifest)
+        .chain(targets)
+        .flat_map(|x| {
+            ["dependencies", "dev-dependencies", "build-dependencies"]
+                .into_iter()
+                .filter_map(|section| x.get(section)?.as_table())
+        })
+        .flatten();
+
+    Ok(dependencies.any(
+        |(key, value)| match value.get("package").and_then(|x| x.as_str()) {
+            Some(package) => package == name,
+            None => key == name,
+        },
+    ))
 }
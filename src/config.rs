@@ -0,0 +1,61 @@
+use globset::{Glob, GlobMatcher};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Name of the config file, looked for in the root of the project.
+pub const CONFIG_FILE: &str = "delta_cmd.toml";
+
+/// Defaults loaded from the config file. Anything passed on the command line takes priority over
+/// these, and these take priority over the built-in defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Overrides for the built-in command templates
+    #[serde(default)]
+    pub templates: Templates,
+    /// Template used by `run` when `--command` isn't passed
+    pub default_command: Option<String>,
+    /// Extra file extensions to consider as source files, used when `--ext` isn't passed
+    #[serde(default)]
+    pub ext: Vec<String>,
+    /// Templates used instead of the command for packages with names matching the glob
+    #[serde(default)]
+    pub packages: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Templates {
+    pub test: Option<String>,
+    pub nextest: Option<String>,
+    pub build: Option<String>,
+    pub bench: Option<String>,
+}
+
+impl Config {
+    /// Loads the config file from `root`, if there isn't one the default config is returned.
+    pub fn load(root: &Path) -> anyhow::Result<Self> {
+        let path = root.join(CONFIG_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let config = fs::read_to_string(&path)?;
+        toml::from_str(&config).map_err(|e| anyhow::anyhow!("invalid {}: {}", path.display(), e))
+    }
+
+    /// Compiles the globs for the per-package templates. If a package matches multiple globs the
+    /// first in alphabetical order is used.
+    pub fn package_templates(&self) -> anyhow::Result<Vec<(GlobMatcher, &str)>> {
+        self.packages
+            .iter()
+            .map(|(glob, template)| {
+                let glob = Glob::new(glob).map_err(|e| {
+                    anyhow::anyhow!("invalid package glob in {}: {}", CONFIG_FILE, e)
+                })?;
+                Ok((glob.compile_matcher(), template.as_str()))
+            })
+            .collect()
+    }
+}
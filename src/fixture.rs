@@ -0,0 +1,158 @@
+//! Git repositories holding a Cargo workspace for tests. This is compiled into both the library
+//! and binary tests which each use different parts of it.
+#![allow(dead_code)]
+
+use git2::{IndexAddOption, Oid, Repository, Signature};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A git repository in a temporary directory, removed when dropped.
+pub struct Fixture {
+    pub root: PathBuf,
+    repo: Repository,
+}
+
+impl Fixture {
+    /// Creates an empty repository. Build output and the lockfile are ignored so running cargo
+    /// doesn't add changes.
+    pub fn new() -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let root = std::env::temp_dir().join(format!("delta_cmd-{}-{}", std::process::id(), id));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let root = fs::canonicalize(root).unwrap();
+        let repo = Repository::init(&root).unwrap();
+        let fixture = Self { root, repo };
+        fixture.write(".gitignore", "target/\nCargo.lock\n");
+        fixture
+    }
+
+    /// Creates a workspace with the given crates and commits it. Each crate is a path relative to
+    /// the root paired with the paths of the crates it depends on, the package name is the last
+    /// component of the path.
+    pub fn workspace(crates: &[(&str, &[&str])]) -> Self {
+        let fixture = Self::new();
+        for (path, dependencies) in crates {
+            fixture.add_crate(path, dependencies);
+        }
+        let members = crates
+            .iter()
+            .map(|(x, _)| format!("{:?}", x))
+            .collect::<Vec<_>>();
+        fixture.write(
+            "Cargo.toml",
+            &format!(
+                "[workspace]\nresolver = \"2\"\nmembers = [{}]\n",
+                members.join(", ")
+            ),
+        );
+        fixture.commit("Initial commit");
+        fixture
+    }
+
+    /// Writes a library crate to `path` depending on the crates at the paths in `dependencies`.
+    pub fn add_crate(&self, path: &str, dependencies: &[&str]) {
+        let name = Path::new(path).file_name().unwrap().to_string_lossy();
+        let mut manifest = format!(
+            "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n",
+            name
+        );
+        for dependency in dependencies {
+            let dependency_name = Path::new(dependency).file_name().unwrap().to_string_lossy();
+            manifest.push_str(&format!(
+                "{} = {{ path = {:?} }}\n",
+                dependency_name,
+                self.root.join(dependency)
+            ));
+        }
+        self.write(&format!("{}/Cargo.toml", path), &manifest);
+        self.write(&format!("{}/src/lib.rs", path), "");
+    }
+
+    /// Absolute path of the package directory at `path`.
+    pub fn dir(&self, path: &str) -> PathBuf {
+        self.root.join(path)
+    }
+
+    pub fn write(&self, path: &str, contents: &str) {
+        let path = self.root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Appends a line to the file, creating it if it doesn't exist.
+    pub fn touch(&self, path: &str) {
+        let contents = fs::read_to_string(self.root.join(path)).unwrap_or_default();
+        self.write(path, &format!("{}// changed\n", contents));
+    }
+
+    /// Removes a file or directory.
+    pub fn remove(&self, path: &str) {
+        let path = self.root.join(path);
+        if path.is_dir() {
+            fs::remove_dir_all(path).unwrap();
+        } else {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    pub fn rename(&self, from: &str, to: &str) {
+        let to = self.root.join(to);
+        fs::create_dir_all(to.parent().unwrap()).unwrap();
+        fs::rename(self.root.join(from), to).unwrap();
+    }
+
+    /// Commits everything in the working tree on top of HEAD.
+    pub fn commit(&self, message: &str) -> Oid {
+        let parents = self.head().into_iter().collect::<Vec<_>>();
+        self.commit_with_parents(message, &parents, true)
+    }
+
+    /// Commits everything in the working tree on top of `parent` without moving HEAD, making a
+    /// commit on another branch.
+    pub fn commit_on(&self, message: &str, parent: Oid) -> Oid {
+        self.commit_with_parents(message, &[parent], false)
+    }
+
+    /// Commits everything in the working tree as a merge of HEAD and `other`.
+    pub fn merge(&self, message: &str, other: Oid) -> Oid {
+        let head = self.head().unwrap();
+        self.commit_with_parents(message, &[head, other], true)
+    }
+
+    pub fn head(&self) -> Option<Oid> {
+        self.repo.head().ok().and_then(|x| x.target())
+    }
+
+    fn commit_with_parents(&self, message: &str, parents: &[Oid], update_head: bool) -> Oid {
+        let mut index = self.repo.index().unwrap();
+        index.add_all(["*"], IndexAddOption::DEFAULT, None).unwrap();
+        index.update_all(["*"], None).unwrap();
+        index.write().unwrap();
+        let tree = self.repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("delta_cmd", "delta_cmd@example.com").unwrap();
+        let parents = parents
+            .iter()
+            .map(|x| self.repo.find_commit(*x).unwrap())
+            .collect::<Vec<_>>();
+        self.repo
+            .commit(
+                update_head.then_some("HEAD"),
+                &signature,
+                &signature,
+                message,
+                &tree,
+                &parents.iter().collect::<Vec<_>>(),
+            )
+            .unwrap()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
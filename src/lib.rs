@@ -0,0 +1,936 @@
+use anyhow::Context;
+use cargo::{DependencyKind, Package};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::warn;
+use radix_trie::{Trie, TrieCommon};
+use repository::{ChangeSource, FileFilter, GitChangeSource};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::{Path, PathBuf};
+
+pub mod cargo;
+#[cfg(test)]
+mod fixture;
+pub mod repository;
+
+pub use cargo::find_packages;
+pub use repository::get_changed_source_files;
+
+/// Options controlling which changes are detected and how they're propagated through the
+/// workspace.
+#[derive(Clone, Debug)]
+pub struct SelectionOptions {
+    /// Git revision to diff against, defaults to the parent of HEAD
+    pub base: Option<String>,
+    /// Start and end revisions to diff between instead of looking at HEAD
+    pub range: Option<(String, String)>,
+    /// When HEAD is a merge commit diff against each of its parents instead of their merge base
+    pub all_parents: bool,
+    /// Decides which changed files are considered
+    pub filter: FileFilter,
+    /// Ignore changed files in directories listed in `workspace.exclude`, as cargo doesn't treat
+    /// them as part of the member they're nested in. On by default
+    pub respect_workspace_excludes: bool,
+    /// The kinds of dependency followed when selecting dependents of changed packages
+    pub kinds: BTreeSet<DependencyKind>,
+    /// How many levels of dependents are selected, unlimited if `None`
+    pub depth: Option<usize>,
+    /// Restricts which packages can end up in the final selection
+    pub package_filter: PackageFilter,
+    /// Run `cargo metadata` even when the cached output is fresh, erroring if they don't match
+    pub abort_on_metadata_drift: bool,
+}
+
+impl Default for SelectionOptions {
+    fn default() -> Self {
+        Self {
+            base: None,
+            range: None,
+            all_parents: false,
+            filter: FileFilter::default(),
+            respect_workspace_excludes: true,
+            kinds: BTreeSet::from(DependencyKind::ALL),
+            depth: None,
+            package_filter: PackageFilter::default(),
+            abort_on_metadata_drift: false,
+        }
+    }
+}
+
+/// Restricts which packages can be selected. This is applied after changes have been propagated
+/// so a package being filtered out doesn't stop its dependents being selected.
+#[derive(Clone, Debug, Default)]
+pub struct PackageFilter {
+    only: Option<GlobSet>,
+    skip: Option<GlobSet>,
+    default_members_only: bool,
+}
+
+impl PackageFilter {
+    /// Create a filter from globs matched against package names. If `only` is non-empty a package
+    /// has to match one of them, and packages matching any glob in `skip` are never selected.
+    pub fn new(
+        only: &[String],
+        skip: &[String],
+        default_members_only: bool,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            only: build_globset(only)?,
+            skip: build_globset(skip)?,
+            default_members_only,
+        })
+    }
+
+    pub fn allows(&self, package: &Package) -> bool {
+        (!self.default_members_only || package.default_member)
+            && self.only.as_ref().is_none_or(|x| x.is_match(&package.name))
+            && !self
+                .skip
+                .as_ref()
+                .is_some_and(|x| x.is_match(&package.name))
+    }
+
+    /// Removes the packages this filter doesn't allow from a set of package directories.
+    pub fn apply(&self, packages: &Trie<PathBuf, Package>, dirs: &mut BTreeSet<PathBuf>) {
+        dirs.retain(|x| packages.get(x).is_some_and(|x| self.allows(x)));
+    }
+}
+
+pub(crate) fn build_globset(globs: &[String]) -> anyhow::Result<Option<GlobSet>> {
+    if globs.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).with_context(|| format!("invalid glob `{}`", glob))?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+impl SelectionOptions {
+    /// Finds the packages in the workspace, checking the cached metadata if asked to.
+    pub fn packages(&self, root: &Path) -> anyhow::Result<Trie<PathBuf, Package>> {
+        if self.abort_on_metadata_drift {
+            cargo::find_packages_checked(root)
+        } else {
+            find_packages(root)
+        }
+    }
+
+    /// Picks the change detection backend based on the options.
+    pub fn change_source(&self, root: &Path) -> Box<dyn ChangeSource> {
+        Box::new(
+            GitChangeSource::new(root)
+                .with_base(self.base.clone())
+                .with_range(self.range.clone())
+                .with_all_parents(self.all_parents)
+                .with_filter(self.filter.clone()),
+        )
+    }
+}
+
+/// Get the names of all the packages affected by the changes since `base` (or the last commit if
+/// no base is provided). This includes packages which depend on a changed package in the
+/// workspace.
+pub fn changed_packages(root: &Path, base: Option<&str>) -> anyhow::Result<BTreeSet<String>> {
+    let opts = SelectionOptions {
+        base: base.map(ToString::to_string),
+        ..Default::default()
+    };
+    affected_packages(root, &opts)
+}
+
+/// Get the names of all the packages affected by the changes picked out by `opts`, including
+/// packages which depend on a changed package in the workspace.
+pub fn affected_packages(root: &Path, opts: &SelectionOptions) -> anyhow::Result<BTreeSet<String>> {
+    let files = changed_files(root, opts)?;
+    let packages = opts.packages(root)?;
+    let source = opts.change_source(root);
+    let deleted = deleted_packages(&packages, root, &files, source.as_ref())?;
+    let mut dirs = select_packages(&packages, root, &files, &deleted, &opts.kinds, opts.depth);
+    dirs.extend(select_renames(&packages, root, &files, opts)?);
+    opts.package_filter.apply(&packages, &mut dirs);
+    Ok(package_names(&packages, &dirs)
+        .into_iter()
+        .map(ToString::to_string)
+        .collect())
+}
+
+/// Get the changed files relative to `root` that are considered for package selection, in sorted
+/// order.
+pub fn changed_files(root: &Path, opts: &SelectionOptions) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = opts.change_source(root).changed_files()?;
+    files.sort();
+    files.dedup();
+
+    if opts.respect_workspace_excludes {
+        let excludes = cargo::workspace_excludes(root)?;
+        files.retain(|x| !excludes.iter().any(|e| root.join(x).starts_with(e)));
+    }
+
+    Ok(files)
+}
+
+/// Why a package was selected.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Reason {
+    /// Files in the package changed, paths are relative to the root
+    Changed(Vec<PathBuf>),
+    /// The package depends on this selected package directory
+    Dependency(PathBuf),
+    /// A file affecting the whole workspace changed, the path is relative to the root
+    Workspace(PathBuf),
+}
+
+/// Files in the root of the workspace which affect every package when changed.
+pub const WORKSPACE_FILES: &[&str] = &["Cargo.toml", "Cargo.lock"];
+
+/// Given a list of changed files relative to `root` returns the directories (trie keys) of every
+/// affected package. `deleted` holds the directories of packages removed by the changes, as found
+/// by [`deleted_packages`]. Only dependencies of the given kinds are followed when looking for
+/// packages that depend on a changed package.
+pub fn select_packages(
+    packages: &Trie<PathBuf, Package>,
+    root: &Path,
+    files: &[PathBuf],
+    deleted: &[PathBuf],
+    kinds: &BTreeSet<DependencyKind>,
+    depth: Option<usize>,
+) -> BTreeSet<PathBuf> {
+    explain_selection(packages, root, files, deleted, kinds, depth)
+        .into_keys()
+        .collect()
+}
+
+/// Like [`select_packages`] but also returns the reason each package was selected.
+pub fn explain_selection(
+    packages: &Trie<PathBuf, Package>,
+    root: &Path,
+    files: &[PathBuf],
+    deleted: &[PathBuf],
+    kinds: &BTreeSet<DependencyKind>,
+    depth: Option<usize>,
+) -> BTreeMap<PathBuf, Reason> {
+    // Now from these files we want to create a list of projects in the workspace we should run
+    // tests on. This is done via two easy checks:
+    //
+    // 1. If a project has a file that changed. Rerun it
+    // 2. If a project has a dependency in the workspace that changed. Run it.
+    let changed_packages = direct_changes(packages, root, files, deleted);
+    propagate(packages, changed_packages, kinds, depth)
+}
+
+/// Selects the publishable packages needing a release: publishable packages which changed and
+/// every publishable package depending on them.
+pub fn select_published(
+    packages: &Trie<PathBuf, Package>,
+    root: &Path,
+    files: &[PathBuf],
+    deleted: &[PathBuf],
+    kinds: &BTreeSet<DependencyKind>,
+    depth: Option<usize>,
+) -> BTreeSet<PathBuf> {
+    let is_published = |dir: &PathBuf| packages.get(dir).is_some_and(|x| x.publish);
+    let mut changed_packages = direct_changes(packages, root, files, deleted);
+    changed_packages.retain(|dir, _| is_published(dir));
+    propagate(packages, changed_packages, kinds, depth)
+        .into_keys()
+        .filter(is_published)
+        .collect()
+}
+
+/// Finds the packages directly affected by the changed files, without looking at dependencies.
+fn direct_changes(
+    packages: &Trie<PathBuf, Package>,
+    root: &Path,
+    files: &[PathBuf],
+    deleted: &[PathBuf],
+) -> BTreeMap<PathBuf, Reason> {
+    // The root manifest can change things like shared dependency versions and profiles, and the
+    // lockfile changing can change any package's dependencies so these select everything
+    if let Some(file) = files
+        .iter()
+        .find(|x| WORKSPACE_FILES.iter().any(|w| x.as_path() == Path::new(w)))
+    {
+        return packages
+            .keys()
+            .map(|dir| (dir.clone(), Reason::Workspace(file.clone())))
+            .collect();
+    }
+
+    // Packages which depended on a deleted package don't need handling here, cargo can't load
+    // the workspace while they still depend on it so their manifests will have changed too
+    attribute_files(packages, root, files, deleted)
+        .into_iter()
+        .map(|(dir, files)| (dir, Reason::Changed(files)))
+        .collect()
+}
+
+/// Adds every package which transitively depends on one of the selected packages via a dependency
+/// of one of the given kinds. With a `depth` only dependents at most that many dependencies away
+/// from the selection are added, so a depth of 0 adds nothing.
+pub fn propagate(
+    packages: &Trie<PathBuf, Package>,
+    mut selection: BTreeMap<PathBuf, Reason>,
+    kinds: &BTreeSet<DependencyKind>,
+    depth: Option<usize>,
+) -> BTreeMap<PathBuf, Reason> {
+    let dependents = reverse_dependencies(packages, kinds);
+
+    // Breadth first so each package is reached at its shortest distance from the selection
+    let mut queue = selection
+        .keys()
+        .map(|x| (x.clone(), 0))
+        .collect::<VecDeque<_>>();
+    while let Some((dir, distance)) = queue.pop_front() {
+        if depth.is_some_and(|x| distance >= x) {
+            continue;
+        }
+        for dependent in dependents.get(dir.as_path()).into_iter().flatten() {
+            if !selection.contains_key(*dependent) {
+                selection.insert(dependent.to_path_buf(), Reason::Dependency(dir.clone()));
+                queue.push_back((dependent.to_path_buf(), distance + 1));
+            }
+        }
+    }
+
+    selection
+}
+
+/// A package whose `package.name` changed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rename {
+    /// Directory of the renamed package
+    pub dir: PathBuf,
+    pub old: String,
+    pub new: String,
+}
+
+/// Finds packages whose manifest changed and has a different name to the one it had before the
+/// changes.
+pub fn renamed_packages(
+    packages: &Trie<PathBuf, Package>,
+    root: &Path,
+    files: &[PathBuf],
+    source: &dyn ChangeSource,
+) -> anyhow::Result<Vec<Rename>> {
+    let mut renames = vec![];
+    for file in files {
+        let Some((dir, package)) = packages
+            .iter()
+            .find(|(_, package)| package.manifest == root.join(file))
+        else {
+            continue;
+        };
+        let Some(old) = source
+            .previous_contents(file)?
+            .and_then(|x| cargo::manifest_package_name(&x))
+        else {
+            continue;
+        };
+        if old != package.name {
+            renames.push(Rename {
+                dir: dir.clone(),
+                old,
+                new: package.name.clone(),
+            });
+        }
+    }
+    Ok(renames)
+}
+
+/// Selects the renamed packages, any package still declaring a dependency on the old name and
+/// everything depending on those.
+pub fn select_renamed(
+    packages: &Trie<PathBuf, Package>,
+    renames: &[Rename],
+    kinds: &BTreeSet<DependencyKind>,
+    depth: Option<usize>,
+) -> anyhow::Result<BTreeSet<PathBuf>> {
+    let mut selection = BTreeMap::new();
+    for rename in renames {
+        selection.insert(rename.dir.clone(), Reason::Changed(vec![]));
+        for (dir, package) in packages.iter() {
+            if cargo::declares_dependency(&package.manifest, &rename.old)? {
+                selection.insert(dir.clone(), Reason::Dependency(rename.dir.clone()));
+            }
+        }
+    }
+    Ok(propagate(packages, selection, kinds, depth)
+        .into_keys()
+        .collect())
+}
+
+/// Finds the packages renamed by the changes, warning about each as `-p` with the old name no
+/// longer works, and selects them as [`select_renamed`] does.
+pub fn select_renames(
+    packages: &Trie<PathBuf, Package>,
+    root: &Path,
+    files: &[PathBuf],
+    opts: &SelectionOptions,
+) -> anyhow::Result<BTreeSet<PathBuf>> {
+    let source = opts.change_source(root);
+    let renames = renamed_packages(packages, root, files, source.as_ref())?;
+    for rename in &renames {
+        warn!(
+            "package `{}` was renamed to `{}`, `-p {}` no longer refers to it",
+            rename.old, rename.new, rename.old
+        );
+    }
+    select_renamed(packages, &renames, &opts.kinds, opts.depth)
+}
+
+/// Selects the packages watching a path with changed files in it, plus their dependents. This
+/// covers inputs outside of the package directory such as shared C sources. `watches` pairs a
+/// path relative to `root` with the name of the package watching it.
+pub fn select_watched(
+    packages: &Trie<PathBuf, Package>,
+    files: &[PathBuf],
+    watches: &[(PathBuf, String)],
+    kinds: &BTreeSet<DependencyKind>,
+    depth: Option<usize>,
+) -> anyhow::Result<BTreeSet<PathBuf>> {
+    let mut changed: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for (path, name) in watches {
+        let dir = packages
+            .iter()
+            .find(|(_, package)| package.name == *name)
+            .map(|(dir, _)| dir)
+            .with_context(|| format!("no package named `{}` to watch {}", name, path.display()))?;
+        let watched = files.iter().filter(|x| x.starts_with(path)).cloned();
+        changed.entry(dir.clone()).or_default().extend(watched);
+    }
+    let selection = changed
+        .into_iter()
+        .filter(|(_, files)| !files.is_empty())
+        .map(|(dir, files)| (dir, Reason::Changed(files)))
+        .collect();
+    Ok(propagate(packages, selection, kinds, depth)
+        .into_keys()
+        .collect())
+}
+
+/// Finds dependencies whose path isn't a package in the workspace, returning the name of the
+/// package declaring the dependency and the path it points at. These are ignored when
+/// propagating changes so can cause dependents to be missed.
+pub fn unresolved_dependencies(packages: &Trie<PathBuf, Package>) -> Vec<(&str, &Path)> {
+    packages
+        .values()
+        .flat_map(|package| {
+            package
+                .dependencies
+                .iter()
+                .filter(|x| packages.get(&x.path).is_none())
+                .map(|x| (package.name.as_str(), x.path.as_path()))
+        })
+        .collect()
+}
+
+/// Maps the directory of each package with changes to the changed files (relative to `root`)
+/// inside it. Files which aren't in any package, or are in one of the `deleted` package
+/// directories, are dropped.
+pub fn attribute_files(
+    packages: &Trie<PathBuf, Package>,
+    root: &Path,
+    files: &[PathBuf],
+    deleted: &[PathBuf],
+) -> BTreeMap<PathBuf, Vec<PathBuf>> {
+    let mut changed_files: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+
+    for file in files {
+        let path = root.join(file);
+        if let Some(dir) = packages.get_ancestor(&path).and_then(|x| x.key().cloned()) {
+            // Files of a deleted package would otherwise be attributed to a package it was nested in
+            let in_deleted = deleted
+                .iter()
+                .any(|x| path.starts_with(x) && !dir.starts_with(x));
+            if !in_deleted {
+                changed_files.entry(dir).or_default().push(file.clone());
+            }
+        }
+    }
+
+    changed_files
+}
+
+/// Finds the directories of packages removed by the changes, these are the directories which
+/// aren't a package in the workspace and whose `Cargo.toml` the source reports as deleted. Asking
+/// the source rather than the working tree means this also works when the changes being looked
+/// at aren't checked out, and manifests which still exist (such as test fixtures nested in a
+/// package) aren't mistaken for deleted packages.
+pub fn deleted_packages(
+    packages: &Trie<PathBuf, Package>,
+    root: &Path,
+    files: &[PathBuf],
+    source: &dyn ChangeSource,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut deleted = vec![];
+    for file in files {
+        if file.file_name().is_none_or(|x| x != "Cargo.toml") {
+            continue;
+        }
+        let Some(dir) = file.parent().map(|x| root.join(x)) else {
+            continue;
+        };
+        if packages.get(&dir).is_none() && source.is_deleted(file)? {
+            deleted.push(dir);
+        }
+    }
+    Ok(deleted)
+}
+
+/// Maps the directory of each package to the directories of the packages in the workspace which
+/// depend on it via a dependency of one of the given kinds.
+pub fn reverse_dependencies<'a>(
+    packages: &'a Trie<PathBuf, Package>,
+    kinds: &BTreeSet<DependencyKind>,
+) -> BTreeMap<&'a Path, BTreeSet<&'a Path>> {
+    let mut dependents: BTreeMap<&Path, BTreeSet<&Path>> = BTreeMap::new();
+    for (dir, package) in packages.iter() {
+        for dependency in package
+            .dependencies
+            .iter()
+            .filter(|x| kinds.contains(&x.kind))
+        {
+            dependents
+                .entry(dependency.path.as_path())
+                .or_default()
+                .insert(dir.as_path());
+        }
+    }
+    dependents
+}
+
+/// Like [`select_packages`] but computes a separate selection for the files of each extension.
+/// Extensions are lowercased and files without an extension are grouped under an empty string.
+/// `deleted` should be found from every changed file, as the deleted manifests are usually in a
+/// different group to the files they affect.
+pub fn select_packages_by_extension(
+    packages: &Trie<PathBuf, Package>,
+    root: &Path,
+    files: &[PathBuf],
+    deleted: &[PathBuf],
+    kinds: &BTreeSet<DependencyKind>,
+    depth: Option<usize>,
+) -> BTreeMap<String, BTreeSet<PathBuf>> {
+    let mut files_by_ext: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for file in files {
+        let ext = file
+            .extension()
+            .map(|x| x.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        files_by_ext.entry(ext).or_default().push(file.clone());
+    }
+
+    files_by_ext
+        .into_iter()
+        .map(|(ext, files)| {
+            let selected = select_packages(packages, root, &files, deleted, kinds, depth);
+            (ext, selected)
+        })
+        .collect()
+}
+
+/// Looks up the names of the packages in the given package directories.
+pub fn package_names<'a>(
+    packages: &'a Trie<PathBuf, Package>,
+    dirs: &BTreeSet<PathBuf>,
+) -> BTreeSet<&'a str> {
+    dirs.iter()
+        .filter_map(|x| packages.get(x))
+        .map(|x| x.name.as_str())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixture::Fixture;
+
+    fn names(packages: &Trie<PathBuf, Package>, dirs: &BTreeSet<PathBuf>) -> Vec<String> {
+        package_names(packages, dirs)
+            .into_iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    /// Builds a workspace from package names paired with the names of the packages they depend
+    /// on, without touching the filesystem.
+    fn graph(crates: &[(&str, &[&str])]) -> Trie<PathBuf, Package> {
+        let mut packages = Trie::new();
+        for (name, dependencies) in crates {
+            let dir = Path::new("/ws").join(name);
+            let package = Package {
+                name: name.to_string(),
+                manifest: dir.join("Cargo.toml"),
+                dependencies: dependencies
+                    .iter()
+                    .map(|x| cargo::Dependency {
+                        path: Path::new("/ws").join(x),
+                        kind: DependencyKind::Normal,
+                    })
+                    .collect(),
+                default_member: true,
+                publish: true,
+            };
+            packages.insert(dir, package);
+        }
+        packages
+    }
+
+    #[test]
+    fn changing_a_dependency_selects_its_dependents() {
+        let fixture = Fixture::workspace(&[("crates/a", &["crates/b"]), ("crates/b", &[])]);
+        fixture.touch("crates/b/src/lib.rs");
+        fixture.commit("Change b");
+
+        let packages = find_packages(&fixture.root).unwrap();
+        let opts = SelectionOptions::default();
+        let files = changed_files(&fixture.root, &opts).unwrap();
+        let selected = select_packages(&packages, &fixture.root, &files, &[], &opts.kinds, None);
+        assert_eq!(names(&packages, &selected), ["a", "b"]);
+        assert_eq!(
+            changed_packages(&fixture.root, None).unwrap(),
+            BTreeSet::from(["a".to_string(), "b".to_string()])
+        );
+    }
+
+    #[test]
+    fn package_names_agree_with_selected_directories() {
+        let packages = graph(&[("a", &["b"]), ("b", &["c"]), ("c", &[]), ("d", &["b"])]);
+        let kinds = BTreeSet::from(DependencyKind::ALL);
+        for changed in ["a", "b", "c", "d"] {
+            let files = [PathBuf::from(changed).join("src/lib.rs")];
+            let selected = select_packages(&packages, Path::new("/ws"), &files, &[], &kinds, None);
+            let names = package_names(&packages, &selected);
+            assert_eq!(names.len(), selected.len());
+            for dir in &selected {
+                assert!(names.contains(packages.get(dir).unwrap().name.as_str()));
+            }
+        }
+    }
+
+    #[test]
+    fn every_transitive_dependent_of_a_deep_chain_is_selected() {
+        // p0 <- p1 <- ... <- p49, each package depending on the one before it
+        let names = (0..50).map(|x| format!("p{}", x)).collect::<Vec<_>>();
+        let dependencies = (0..names.len())
+            .map(|i| names[..i].last().map(String::as_str).into_iter().collect())
+            .collect::<Vec<Vec<_>>>();
+        let crates = names
+            .iter()
+            .zip(&dependencies)
+            .map(|(name, dependencies)| (name.as_str(), dependencies.as_slice()))
+            .collect::<Vec<_>>();
+        let packages = graph(&crates);
+        let kinds = BTreeSet::from(DependencyKind::ALL);
+
+        let files = [PathBuf::from("p0/src/lib.rs")];
+        let selected = select_packages(&packages, Path::new("/ws"), &files, &[], &kinds, None);
+        assert_eq!(selected.len(), names.len());
+
+        let files = [PathBuf::from("p25/src/lib.rs")];
+        let selected = select_packages(&packages, Path::new("/ws"), &files, &[], &kinds, None);
+        assert_eq!(
+            selected,
+            names[25..]
+                .iter()
+                .map(|x| Path::new("/ws").join(x))
+                .collect()
+        );
+    }
+
+    #[test]
+    fn glob_members_and_excluded_directories_match_cargo() {
+        let fixture = Fixture::new();
+        fixture.add_crate("crates/a", &[]);
+        fixture.add_crate("crates/a/fuzz", &["crates/a"]);
+        fixture.add_crate("crates/b", &[]);
+        fixture.add_crate("crates/c", &[]);
+        fixture.write(
+            "Cargo.toml",
+            "[workspace]\nresolver = \"2\"\nmembers = [\"crates/*\"]\nexclude = [\"crates/a/fuzz\", \"crates/c\"]\n",
+        );
+        fixture.commit("Initial commit");
+        fixture.touch("crates/a/fuzz/src/lib.rs");
+        fixture.touch("crates/b/src/lib.rs");
+        fixture.touch("crates/c/src/lib.rs");
+        fixture.commit("Change excluded crates and b");
+
+        let packages = find_packages(&fixture.root).unwrap();
+        let members = packages
+            .values()
+            .map(|x| x.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(members, ["a", "b"]);
+
+        let opts = SelectionOptions::default();
+        assert_eq!(
+            affected_packages(&fixture.root, &opts).unwrap(),
+            BTreeSet::from(["b".to_string()])
+        );
+
+        let opts = SelectionOptions {
+            respect_workspace_excludes: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            affected_packages(&fixture.root, &opts).unwrap(),
+            BTreeSet::from(["a".to_string(), "b".to_string()])
+        );
+    }
+
+    #[test]
+    fn moving_a_file_between_crates_selects_both() {
+        let fixture =
+            Fixture::workspace(&[("crates/a", &[]), ("crates/b", &[]), ("crates/c", &[])]);
+        fixture.write(
+            "crates/a/src/moved.rs",
+            "pub fn moved() -> u32 {\n    1 + 2 + 3 + 4 + 5\n}\n",
+        );
+        fixture.commit("Add moved.rs to a");
+        fixture.rename("crates/a/src/moved.rs", "crates/b/src/moved.rs");
+        fixture.commit("Move moved.rs to b");
+
+        let opts = SelectionOptions::default();
+        assert_eq!(
+            changed_files(&fixture.root, &opts).unwrap(),
+            [
+                Path::new("crates/a/src/moved.rs"),
+                Path::new("crates/b/src/moved.rs"),
+            ]
+        );
+        assert_eq!(
+            affected_packages(&fixture.root, &opts).unwrap(),
+            BTreeSet::from(["a".to_string(), "b".to_string()])
+        );
+    }
+
+    #[test]
+    fn editing_the_root_manifest_selects_every_package() {
+        let fixture = Fixture::workspace(&[
+            ("crates/a", &["crates/b"]),
+            ("crates/b", &[]),
+            ("crates/c", &[]),
+        ]);
+        let manifest = std::fs::read_to_string(fixture.root.join("Cargo.toml")).unwrap();
+        fixture.write(
+            "Cargo.toml",
+            &format!("{}\n[profile.dev]\nopt-level = 1\n", manifest),
+        );
+        fixture.commit("Change the dev profile");
+
+        assert_eq!(
+            changed_packages(&fixture.root, None).unwrap(),
+            BTreeSet::from(["a".to_string(), "b".to_string(), "c".to_string()])
+        );
+    }
+
+    #[test]
+    fn depth_limits_how_many_levels_of_dependents_are_selected() {
+        // c depends on b which depends on a
+        let packages = graph(&[("a", &[]), ("b", &["a"]), ("c", &["b"])]);
+        let kinds = BTreeSet::from(DependencyKind::ALL);
+        let files = [PathBuf::from("a/src/lib.rs")];
+        let select = |depth| {
+            let selected = select_packages(&packages, Path::new("/ws"), &files, &[], &kinds, depth);
+            names(&packages, &selected)
+        };
+        assert_eq!(select(Some(0)), ["a"]);
+        assert_eq!(select(Some(1)), ["a", "b"]);
+        assert_eq!(select(Some(2)), ["a", "b", "c"]);
+        assert_eq!(select(None), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn changes_to_watched_paths_select_the_watching_package_and_its_dependents() {
+        // a builds the vendored C library, b depends on a and c is unrelated
+        let packages = graph(&[("a", &[]), ("b", &["a"]), ("c", &[])]);
+        let kinds = BTreeSet::from(DependencyKind::ALL);
+        let watches = [(PathBuf::from("vendor/libfoo"), "a".to_string())];
+        let select = |file: &str| {
+            let selected =
+                select_watched(&packages, &[PathBuf::from(file)], &watches, &kinds, None).unwrap();
+            names(&packages, &selected)
+        };
+        assert_eq!(select("vendor/libfoo/src/foo.c"), ["a", "b"]);
+        assert!(select("vendor/libbar/src/bar.c").is_empty());
+        assert!(select("docs/libfoo.md").is_empty());
+    }
+
+    #[test]
+    fn deleting_a_nested_leaf_crate_selects_its_former_dependents() {
+        let fixture = Fixture::new();
+        fixture.add_crate("crates/a", &["crates/c/nested/b"]);
+        fixture.add_crate("crates/c", &[]);
+        fixture.add_crate("crates/c/nested/b", &[]);
+        fixture.add_crate("crates/c/nested/d", &[]);
+        fixture.write(
+            "Cargo.toml",
+            "[workspace]\nresolver = \"2\"\nmembers = [\"crates/*\", \"crates/c/nested/*\"]\n",
+        );
+        fixture.commit("Initial commit");
+
+        // a has to stop depending on b for the workspace to load
+        fixture.remove("crates/c/nested/b");
+        fixture.add_crate("crates/a", &[]);
+        fixture.commit("Remove b");
+
+        let opts = SelectionOptions::default();
+        let files = changed_files(&fixture.root, &opts).unwrap();
+        let packages = find_packages(&fixture.root).unwrap();
+        let source = opts.change_source(&fixture.root);
+        let deleted = deleted_packages(&packages, &fixture.root, &files, source.as_ref()).unwrap();
+        assert_eq!(deleted, [fixture.dir("crates/c/nested/b")]);
+        assert_eq!(
+            affected_packages(&fixture.root, &opts).unwrap(),
+            BTreeSet::from(["a".to_string()])
+        );
+
+        // The deleted manifest and the deleted sources are in different groups
+        let kinds = BTreeSet::from(DependencyKind::ALL);
+        let by_extension =
+            select_packages_by_extension(&packages, &fixture.root, &files, &deleted, &kinds, None);
+        assert_eq!(by_extension["rs"], BTreeSet::new());
+        assert_eq!(
+            by_extension["toml"],
+            BTreeSet::from([fixture.dir("crates/a")])
+        );
+    }
+
+    #[test]
+    fn changing_a_fixture_project_selects_the_package_containing_it() {
+        let fixture = Fixture::workspace(&[("crates/a", &[]), ("crates/b", &[])]);
+        fixture.add_crate("crates/a/tests/fixtures/proj", &[]);
+        fixture.commit("Add a fixture project");
+
+        fixture.touch("crates/a/tests/fixtures/proj/Cargo.toml");
+        fixture.touch("crates/a/tests/fixtures/proj/src/lib.rs");
+        fixture.commit("Change the fixture project");
+
+        let opts = SelectionOptions::default();
+        let files = changed_files(&fixture.root, &opts).unwrap();
+        let packages = find_packages(&fixture.root).unwrap();
+        let source = opts.change_source(&fixture.root);
+        assert_eq!(
+            deleted_packages(&packages, &fixture.root, &files, source.as_ref()).unwrap(),
+            Vec::<PathBuf>::new()
+        );
+        assert_eq!(
+            affected_packages(&fixture.root, &opts).unwrap(),
+            BTreeSet::from(["a".to_string()])
+        );
+    }
+
+    #[test]
+    fn cached_metadata_sees_new_glob_members() {
+        let fixture = Fixture::new();
+        fixture.add_crate("crates/a", &[]);
+        fixture.add_crate("crates/b", &[]);
+        fixture.write(
+            "Cargo.toml",
+            "[workspace]\nresolver = \"2\"\nmembers = [\"crates/*\"]\n",
+        );
+        fixture.commit("Initial commit");
+        // The first run creates the lockfile, which the cache is keyed on
+        find_packages(&fixture.root).unwrap();
+        find_packages(&fixture.root).unwrap();
+
+        fixture.add_crate("crates/d", &[]);
+        fixture.commit("Add d");
+        assert_eq!(
+            changed_packages(&fixture.root, None).unwrap(),
+            BTreeSet::from(["d".to_string()])
+        );
+    }
+
+    #[test]
+    fn renamed_packages_are_selected() {
+        let fixture = Fixture::workspace(&[("crates/a", &[]), ("crates/b", &[])]);
+        let manifest = std::fs::read_to_string(fixture.dir("crates/b/Cargo.toml")).unwrap();
+        fixture.write(
+            "crates/b/Cargo.toml",
+            &manifest.replace("name = \"b\"", "name = \"bee\""),
+        );
+        fixture.commit("Rename b");
+
+        let opts = SelectionOptions::default();
+        let files = changed_files(&fixture.root, &opts).unwrap();
+        let packages = find_packages(&fixture.root).unwrap();
+        let source = opts.change_source(&fixture.root);
+        assert_eq!(
+            renamed_packages(&packages, &fixture.root, &files, source.as_ref()).unwrap(),
+            [Rename {
+                dir: fixture.dir("crates/b"),
+                old: "b".to_string(),
+                new: "bee".to_string(),
+            }]
+        );
+        assert_eq!(
+            affected_packages(&fixture.root, &opts).unwrap(),
+            BTreeSet::from(["bee".to_string()])
+        );
+    }
+
+    #[test]
+    fn metadata_drift_is_reported() {
+        let fixture = Fixture::workspace(&[("crates/a", &[]), ("crates/b", &[])]);
+        find_packages(&fixture.root).unwrap();
+        find_packages(&fixture.root).unwrap();
+
+        // Make a depend on b without the cache noticing
+        let manifest = fixture.dir("crates/a/Cargo.toml");
+        let modified = std::fs::metadata(&manifest).unwrap().modified().unwrap();
+        fixture.add_crate("crates/a", &["crates/b"]);
+        std::fs::File::options()
+            .write(true)
+            .open(&manifest)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let a = |packages: &Trie<PathBuf, Package>| {
+            packages
+                .get(&fixture.dir("crates/a"))
+                .unwrap()
+                .dependencies
+                .len()
+        };
+        assert_eq!(a(&find_packages(&fixture.root).unwrap()), 0);
+
+        let opts = SelectionOptions {
+            abort_on_metadata_drift: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            opts.packages(&fixture.root).unwrap_err().to_string(),
+            "cached cargo metadata doesn't match `cargo metadata`: changed `a`"
+        );
+        assert_eq!(a(&find_packages(&fixture.root).unwrap()), 1);
+        assert_eq!(a(&opts.packages(&fixture.root).unwrap()), 1);
+    }
+
+    #[test]
+    fn dependencies_on_members_without_a_path_are_followed() {
+        let fixture = Fixture::new();
+        fixture.add_crate("crates/a", &[]);
+        fixture.add_crate("crates/b", &[]);
+        fixture.add_crate("crates/c", &[]);
+        fixture.write(
+            "crates/a/Cargo.toml",
+            "[package]\nname = \"a\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\nb = \"0.1\"\n",
+        );
+        fixture.write(
+            "Cargo.toml",
+            "[workspace]\nresolver = \"2\"\nmembers = [\"crates/*\"]\n\n[patch.crates-io]\nb = { path = \"crates/b\" }\n",
+        );
+        // The patch means the registry is never needed
+        fixture.write(".cargo/config.toml", "[net]\noffline = true\n");
+        fixture.commit("Initial commit");
+        fixture.touch("crates/b/src/lib.rs");
+        fixture.commit("Change b");
+
+        assert_eq!(
+            changed_packages(&fixture.root, None).unwrap(),
+            BTreeSet::from(["a".to_string(), "b".to_string()])
+        );
+    }
+}
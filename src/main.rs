@@ -1,21 +1,35 @@
 use anyhow::Context;
-use cargo::Package;
-use clap::Parser;
+use clap::{ArgAction, Parser, ValueEnum};
+use config::Config;
+use dc::cargo::{self, DependencyKind, Package};
+use dc::repository::FileFilter;
+use dc::{PackageFilter, Reason, SelectionOptions};
+use log::{LevelFilter, debug, error, info, warn};
 use minijinja::{Environment, Value};
 use radix_trie::{Trie, TrieCommon};
 use std::borrow::Cow;
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::env;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::io::{BufRead, BufReader, Read};
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-mod cargo;
-mod repository;
+mod config;
+#[cfg(test)]
+mod fixture;
 
-const CARGO_TEST_TEMPLATE: &'static str = "cargo test {% for pkg in packages %} -p {{ pkg }} {% endfor %} {% for arg in args %} {{ arg }} {% endfor %}";
-const CARGO_NEXTEST_TEMPLATE: &'static str = "cargo nextest {% for pkg in packages %} -p {{ pkg }} {% endfor %} {% for arg in args %} {{ arg }} {% endfor %}";
-const CARGO_BUILD_TEMPLATE: &'static str = "cargo build {% for pkg in packages %} -p {{ pkg }} {% endfor %} {% for arg in args %} {{ arg }} {% endfor %}";
-const CARGO_BENCH_TEMPLATE: &'static str = "cargo build {% for pkg in packages %} -p {{ pkg }} {% endfor %} {% for arg in args %} {{ arg }} {% endfor %}";
+const CARGO_TEST_TEMPLATE: &str = "cargo test {% for pkg in packages %} -p {{ pkg }} {% endfor %} {% if all_features %} --all-features {% elif features %} --features {{ features }} {% endif %} {% if cargo_jobs %} --jobs {{ cargo_jobs }} {% endif %} {% for arg in args %} {{ arg }} {% endfor %}";
+const CARGO_NEXTEST_TEMPLATE: &str = "cargo nextest {% for pkg in packages %} -p {{ pkg }} {% endfor %} {% if all_features %} --all-features {% elif features %} --features {{ features }} {% endif %} {% if cargo_jobs %} --build-jobs {{ cargo_jobs }} {% endif %} {% for arg in args %} {{ arg }} {% endfor %}";
+const CARGO_BUILD_TEMPLATE: &str = "cargo build {% for pkg in packages %} -p {{ pkg }} {% endfor %} {% if all_features %} --all-features {% elif features %} --features {{ features }} {% endif %} {% if cargo_jobs %} --jobs {{ cargo_jobs }} {% endif %} {% for arg in args %} {{ arg }} {% endfor %}";
+const CARGO_BENCH_TEMPLATE: &str = "cargo bench {% for pkg in packages %} -p {{ pkg }} {% endfor %} {% if all_features %} --all-features {% elif features %} --features {{ features }} {% endif %} {% if cargo_jobs %} --jobs {{ cargo_jobs }} {% endif %} {% for arg in args %} {{ arg }} {% endfor %}";
+
+const CARGO_TEST_EXCLUDE_TEMPLATE: &str = "cargo test --workspace {% for pkg in excludes %} --exclude {{ pkg }} {% endfor %} {% if all_features %} --all-features {% elif features %} --features {{ features }} {% endif %} {% if cargo_jobs %} --jobs {{ cargo_jobs }} {% endif %} {% for arg in args %} {{ arg }} {% endfor %}";
+const CARGO_NEXTEST_EXCLUDE_TEMPLATE: &str = "cargo nextest --workspace {% for pkg in excludes %} --exclude {{ pkg }} {% endfor %} {% if all_features %} --all-features {% elif features %} --features {{ features }} {% endif %} {% if cargo_jobs %} --build-jobs {{ cargo_jobs }} {% endif %} {% for arg in args %} {{ arg }} {% endfor %}";
+const CARGO_BUILD_EXCLUDE_TEMPLATE: &str = "cargo build --workspace {% for pkg in excludes %} --exclude {{ pkg }} {% endfor %} {% if all_features %} --all-features {% elif features %} --features {{ features }} {% endif %} {% if cargo_jobs %} --jobs {{ cargo_jobs }} {% endif %} {% for arg in args %} {{ arg }} {% endfor %}";
+const CARGO_BENCH_EXCLUDE_TEMPLATE: &str = "cargo bench --workspace {% for pkg in excludes %} --exclude {{ pkg }} {% endfor %} {% if all_features %} --all-features {% elif features %} --features {{ features }} {% endif %} {% if cargo_jobs %} --jobs {{ cargo_jobs }} {% endif %} {% for arg in args %} {{ arg }} {% endfor %}";
 
 #[derive(Debug, Parser)]
 pub enum RunCommand {
@@ -34,25 +48,288 @@ impl RunCommand {
         }
     }
 
-    pub fn command(&self) -> Option<Cow<'_, str>> {
-        match self {
-            Self::Test(_) => Some(CARGO_TEST_TEMPLATE.into()),
-            Self::Nextest(_) => Some(CARGO_NEXTEST_TEMPLATE.into()),
-            Self::Build(_) => Some(CARGO_BUILD_TEMPLATE.into()),
-            Self::Bench(_) => Some(CARGO_BENCH_TEMPLATE.into()),
-            Self::Run(a) => a.command.as_ref().map(|x| x.into()),
+    /// Get the command template. Templates passed on the command line are used first, then
+    /// templates from the config file and finally the built-in templates. `use_excludes` picks
+    /// the exclude form of the built-in templates, which `--exclude-mode` and crossing
+    /// `--all-threshold` both ask for. Only `--exclude-mode` overrides a config template.
+    pub fn command<'a>(&'a self, config: &'a Config, use_excludes: bool) -> Option<Cow<'a, str>> {
+        let (preset, config_template) = match self {
+            Self::Test(_) => (Preset::Test, &config.templates.test),
+            Self::Nextest(_) => (Preset::Nextest, &config.templates.nextest),
+            Self::Build(_) => (Preset::Build, &config.templates.build),
+            Self::Bench(_) => (Preset::Bench, &config.templates.bench),
+            Self::Run(a) => {
+                return a
+                    .command
+                    .as_ref()
+                    .or(config.default_command.as_ref())
+                    .map(|x| x.into());
+            }
+        };
+        let required = self.required_args();
+        match config_template {
+            Some(template) if !required.exclude_mode => Some(template.into()),
+            _ => Some(preset.template(use_excludes).into()),
+        }
+    }
+}
+
+/// One of the built-in command templates.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, ValueEnum)]
+pub enum Preset {
+    Test,
+    Nextest,
+    Build,
+    Bench,
+}
+
+impl Preset {
+    /// Get the template for the preset. In exclude mode the template runs on the whole workspace
+    /// and excludes the packages that weren't selected, rather than listing the selected ones.
+    pub fn template(&self, exclude_mode: bool) -> &'static str {
+        match (self, exclude_mode) {
+            (Self::Test, false) => CARGO_TEST_TEMPLATE,
+            (Self::Nextest, false) => CARGO_NEXTEST_TEMPLATE,
+            (Self::Build, false) => CARGO_BUILD_TEMPLATE,
+            (Self::Bench, false) => CARGO_BENCH_TEMPLATE,
+            (Self::Test, true) => CARGO_TEST_EXCLUDE_TEMPLATE,
+            (Self::Nextest, true) => CARGO_NEXTEST_EXCLUDE_TEMPLATE,
+            (Self::Build, true) => CARGO_BUILD_EXCLUDE_TEMPLATE,
+            (Self::Bench, true) => CARGO_BENCH_EXCLUDE_TEMPLATE,
         }
     }
 }
 
+/// Parses an `<EXT>=<PRESET>` pair for `--ext-map`.
+fn parse_ext_map(s: &str) -> Result<(String, Preset), String> {
+    let (ext, preset) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected `<EXT>=<PRESET>` but got `{}`", s))?;
+    let preset = Preset::from_str(preset, true)?;
+    Ok((ext.trim_start_matches('.').to_ascii_lowercase(), preset))
+}
+
+fn parse_watch(s: &str) -> Result<(PathBuf, String), String> {
+    let (path, package) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected `<PATH>=<PKG>` but got `{}`", s))?;
+    let path = Path::new(path)
+        .components()
+        .filter(|x| *x != Component::CurDir)
+        .collect();
+    Ok((path, package.to_string()))
+}
+
+fn parse_range(s: &str) -> Result<(String, String), String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| format!("expected `<A>..<B>` but got `{}`", s))?;
+    if end.starts_with('.') {
+        return Err(format!("`A...B` ranges aren't supported, got `{}`", s));
+    }
+    if start.is_empty() || end.is_empty() {
+        return Err(format!(
+            "expected `<A>..<B>` with both revisions given but got `{}`",
+            s
+        ));
+    }
+    Ok((start.to_string(), end.to_string()))
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ExtMode {
+    /// Consider the default extensions as well as the ones passed via `--ext`
+    #[default]
+    Append,
+    /// Only consider the extensions passed via `--ext`
+    Replace,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable output
+    #[default]
+    Text,
+    /// JSON object with the `packages` to include and the `excludes`
+    Json,
+    /// One selected package name per line
+    Lines,
+    /// Cargo arguments running on every package that wasn't selected, e.g.
+    /// `--workspace --exclude a --exclude b`. Empty when every package is selected
+    CargoExclude,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT. Changed packages are red and packages selected through a dependency are
+    /// yellow, with the dependency that selected them drawn in bold
+    Dot,
+}
+
 #[derive(Debug, Parser)]
 pub struct RequiredArgs {
-    /// Get the project to run on, runs in current directory otherwise.
+    /// Get the project to run on, runs in current directory otherwise. This can be any directory
+    /// in the workspace, the workspace root is found from it and commands are ran from there.
     #[arg(short, long)]
     input: Option<PathBuf>,
+    /// Git revision to diff HEAD against, defaults to the parent of HEAD
+    #[arg(long)]
+    base: Option<String>,
+    /// Changes to files under PATH (relative to the root) select the package PKG, for inputs
+    /// outside of the package such as shared C sources. Can be repeated
+    #[arg(long, value_name = "PATH=PKG", value_parser = parse_watch)]
+    watch: Vec<(PathBuf, String)>,
+    /// Diff the tree of revision A against revision B instead of looking at HEAD, e.g.
+    /// `--range main..my-feature`. As both ends are given this can't be combined with `--base`
+    /// or `--all-parents`
+    #[arg(long, value_name = "A..B", value_parser = parse_range, conflicts_with_all = ["base", "all_parents"])]
+    range: Option<(String, String)>,
+    /// When HEAD is a merge commit diff against each of its parents instead of against the merge
+    /// base of the parents
+    #[arg(long)]
+    all_parents: bool,
+    /// Extra file extensions to consider as source files, either comma separated or repeated
+    #[arg(long = "ext", value_delimiter = ',')]
+    extensions: Vec<String>,
+    /// Consider changed files matching this glob (relative to the root) whatever their
+    /// extension, can be repeated
+    #[arg(long, value_name = "GLOB")]
+    include_glob: Vec<String>,
+    /// Ignore changed files matching this glob (relative to the root), this wins over
+    /// `--include-glob`. Can be repeated
+    #[arg(long, value_name = "GLOB")]
+    exclude_glob: Vec<String>,
+    /// Whether `--ext` extends or replaces the default extensions
+    #[arg(long, value_enum, default_value_t)]
+    ext_mode: ExtMode,
+    /// Only consider the extensions passed via `--ext`, shorthand for `--ext-mode replace`
+    #[arg(long, requires = "extensions")]
+    only_ext: bool,
+    /// Run a built-in command over the packages affected by files with the given extension
+    /// instead of the main command, e.g. `--ext-map proto=build`. Can be repeated
+    #[arg(long, value_parser = parse_ext_map)]
+    ext_map: Vec<(String, Preset)>,
+    /// Resolve workspace membership the way cargo does so files in directories listed in
+    /// `workspace.exclude` aren't attributed to a member they're nested in. Set to false to
+    /// attribute them to that member anyway
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    resolve_workspace_globs: bool,
+    /// Only select packages whose name matches one of these globs, can be repeated
+    #[arg(long)]
+    only: Vec<String>,
+    /// Never select packages whose name matches one of these globs, can be repeated
+    #[arg(long)]
+    skip: Vec<String>,
+    /// Never select this package, can be repeated
+    #[arg(long, value_name = "PACKAGE")]
+    exclude: Vec<String>,
+    /// If more than this many packages are selected run on the whole workspace instead, still
+    /// respecting `--exclude`
+    #[arg(long, value_name = "N")]
+    all_threshold: Option<usize>,
+    /// Only select packages in the workspace's `default-members`
+    #[arg(long)]
+    default_members_only: bool,
+    /// How many levels of dependents of changed packages to select. 0 only selects the changed
+    /// packages, 1 adds the packages depending on them and so on. Unlimited by default
+    #[arg(long, value_name = "N")]
+    depth: Option<usize>,
+    /// Select packages which have a changed package as a dev-dependency
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    include_dev: bool,
+    /// Select packages which have a changed package as a build-dependency
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    include_build: bool,
+    /// Run the generated command under this wrapper, e.g. `--prefix-command 'nice -n 10'`
+    #[arg(long, value_name = "CMD")]
+    prefix_command: Option<String>,
+    /// Make the built-in commands run on the whole workspace and `--exclude` every package that
+    /// wasn't selected instead of passing `-p` for each selected package
+    #[arg(long)]
+    exclude_mode: bool,
     /// Generate command but don't run it
     #[arg(long)]
     no_run: bool,
+    /// Run the command once per selected package instead of once for all of them. Stops at the
+    /// first package which fails unless `--keep-going` is set
+    #[arg(long)]
+    per_package: bool,
+    /// With `--per-package` run every package even if some fail, exiting with an error at the end
+    #[arg(long, requires = "per_package")]
+    keep_going: bool,
+    /// With `--per-package` the maximum number of package commands to run at once
+    #[arg(long, value_name = "N", default_value = "1", requires = "per_package")]
+    parallel: NonZeroUsize,
+    /// Features to enable, either comma separated or repeated. Passed to the built-in commands and
+    /// available to templates as `features`
+    #[arg(long, value_delimiter = ',')]
+    features: Vec<String>,
+    /// Enable all features. Passed to the built-in commands and available to templates as
+    /// `all_features`
+    #[arg(long, conflicts_with = "features")]
+    all_features: bool,
+    /// Passed to each cargo command as `--jobs`. Defaults to splitting the available cores
+    /// between the `--parallel` commands, or cargo's own default when only one runs at a time
+    #[arg(long, value_name = "N")]
+    cargo_jobs: Option<NonZeroUsize>,
+    /// With `--per-package` first build all the selected packages in one command so the
+    /// per-package commands start with a warm target directory
+    #[arg(long, requires = "per_package")]
+    warmup: bool,
+    /// Run `cargo metadata` even when its cached output can be used, exiting with an error listing
+    /// the differences if they don't match
+    #[arg(long)]
+    abort_on_metadata_drift: bool,
+    /// Check every dependency path in the workspace belongs to a package before selecting
+    /// anything, exiting with an error listing any that don't
+    #[arg(long)]
+    verify_dependencies_resolve: bool,
+    /// When no packages are selected print nothing to stdout and don't run the command, so
+    /// capturing the output gives an empty string
+    #[arg(long)]
+    null_on_empty: bool,
+    /// Only print the number of affected packages
+    #[arg(long)]
+    count_only: bool,
+    /// Template used to print the selection when no command is run. Has the same variables as
+    /// the command template plus `count`, e.g. `{{ count }} crates: {{ packages | join(',') }}`
+    #[arg(long, value_name = "STR", conflicts_with = "format")]
+    output_template: Option<String>,
+    /// Print GitHub Actions notices explaining why each package was selected
+    #[arg(long)]
+    github_annotations: bool,
+    /// Print why each package was selected instead of running anything
+    #[arg(long)]
+    explain: bool,
+    /// Print the dependency graph of the workspace with the selection highlighted instead of
+    /// running anything
+    #[arg(long, value_enum, value_name = "FORMAT", conflicts_with = "explain")]
+    graph: Option<GraphFormat>,
+    /// Print the changed files in the named package relative to its directory and exit
+    #[arg(long, value_name = "NAME")]
+    changed_within_package: Option<String>,
+    /// Print the publishable packages which changed, along with every publishable package
+    /// depending on them, as JSON and exit. Useful to find what needs a release
+    #[arg(long)]
+    select_reverse_only_published: bool,
+    /// Print diagnostics to stderr, repeat for more detail. `RUST_LOG` takes priority if set
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Only print the result and errors, hiding warnings, status messages and successes in the
+    /// `--per-package` summary. The output of the command itself is left alone
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Format used to print the selected packages when no command is run
+    #[arg(long, value_enum, default_value_t)]
+    format: OutputFormat,
+    /// Print the selected packages as cargo arguments (`-p a -p b`) instead of running anything,
+    /// for use as `cargo test $(dc test --packages-only)`. Combine with `--format` for other
+    /// forms such as one package per line
+    #[arg(long)]
+    packages_only: bool,
+    /// Pretty print JSON output instead of printing it on a single line
+    #[arg(long)]
+    json_pretty: bool,
     /// These will be passed to the minijinja template as the args variable
     #[arg(last = true)]
     args: Vec<String>,
@@ -65,12 +342,95 @@ impl RequiredArgs {
             None => env::current_dir().unwrap(),
         }
     }
+
+    /// The root of the workspace containing the input directory.
+    fn root(&self) -> anyhow::Result<PathBuf> {
+        cargo::workspace_root(&self.path())
+    }
+
+    fn file_filter(&self, config: &Config) -> anyhow::Result<FileFilter> {
+        let extensions = if self.extensions.is_empty() {
+            &config.ext
+        } else {
+            &self.extensions
+        };
+        let mut filter = if self.only_ext || self.ext_mode == ExtMode::Replace {
+            FileFilter::new(extensions)
+        } else {
+            let mut filter = FileFilter::default();
+            filter.extend(extensions);
+            filter
+        };
+        // Mapping an extension would do nothing if files with it were ignored
+        filter.extend(self.ext_map.iter().map(|(ext, _)| ext));
+        filter.set_globs(&self.include_glob, &self.exclude_glob)?;
+        Ok(filter)
+    }
+
+    /// The jobs to forward to cargo. Unless set explicitly the cores are split between the
+    /// commands running in parallel so they don't fight over them, warning if an explicit value
+    /// oversubscribes the cores.
+    fn cargo_jobs(&self) -> Option<NonZeroUsize> {
+        let parallel = if self.per_package {
+            self.parallel.get()
+        } else {
+            1
+        };
+        let cores = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        match self.cargo_jobs {
+            Some(jobs) => {
+                if parallel > 1 && jobs.get() * parallel > cores {
+                    warn!(
+                        "{} parallel commands with {} cargo jobs each oversubscribes the {} available cores",
+                        parallel, jobs, cores
+                    );
+                }
+                Some(jobs)
+            }
+            None if parallel > 1 => NonZeroUsize::new((cores / parallel).max(1)),
+            None => None,
+        }
+    }
+
+    /// The kinds of dependency followed when selecting dependents of changed packages.
+    fn dependency_kinds(&self) -> BTreeSet<DependencyKind> {
+        let mut kinds = BTreeSet::from([DependencyKind::Normal]);
+        if self.include_dev {
+            kinds.insert(DependencyKind::Dev);
+        }
+        if self.include_build {
+            kinds.insert(DependencyKind::Build);
+        }
+        kinds
+    }
+
+    fn selection_options(&self, config: &Config) -> anyhow::Result<SelectionOptions> {
+        Ok(SelectionOptions {
+            base: self.base.clone(),
+            range: self.range.clone(),
+            all_parents: self.all_parents,
+            filter: self.file_filter(config)?,
+            respect_workspace_excludes: self.resolve_workspace_globs,
+            kinds: self.dependency_kinds(),
+            depth: self.depth,
+            package_filter: PackageFilter::new(
+                &self.only,
+                &[self.skip.as_slice(), self.exclude.as_slice()].concat(),
+                self.default_members_only,
+            )?,
+            abort_on_metadata_drift: self.abort_on_metadata_drift,
+        })
+    }
 }
 
 #[derive(Debug, Parser)]
 pub struct Args {
     /// Run the following command. This accepts a minijinja template where `packages` is a list of
-    /// packages that can be included and `excludes` is a list of packages that can be excluded.
+    /// packages that can be included, `excludes` is a list of packages that can be excluded and
+    /// `changed_files` (or `files`) is a sorted list of the changed files. `package_files` maps
+    /// each package to the changed files in it, for use as `{% for pkg, files in
+    /// package_files|items %}`. `features` and `all_features` come from `--features` and
+    /// `--all-features`.
     /// For a cargo test you can write the template `cargo test {% for pkg in packages %} -p {{ pkg
     /// }}{% endfor %}`
     #[arg(short, long)]
@@ -89,12 +449,108 @@ fn generate_exclude_list<'a>(
         .collect::<BTreeSet<_>>()
 }
 
-fn generate_command(
+/// Cargo arguments selecting every package other than the excluded ones, empty if nothing is
+/// excluded.
+fn exclude_args<'a>(
+    packages: impl Iterator<Item = &'a Package>,
+    included_packages: &BTreeSet<&str>,
+) -> Vec<&'a str> {
+    let excludes = generate_exclude_list(packages, included_packages);
+    if excludes.is_empty() {
+        return vec![];
+    }
+    let mut args = vec!["--workspace"];
+    for package in excludes {
+        args.extend(["--exclude", package]);
+    }
+    args
+}
+
+/// The data available to command templates, other than the selected packages.
+#[derive(Clone, Copy)]
+struct TemplateContext<'a> {
+    packages: &'a Trie<PathBuf, Package>,
+    /// Changed files relative to the root
+    changed_files: &'a [PathBuf],
+    /// Changed files relative to the root for each package with changes, keyed by package name
+    package_files: &'a BTreeMap<&'a str, Vec<PathBuf>>,
+    args: &'a [String],
+    /// Root of the workspace, commands are ran from here so they can use the changed file paths
+    root: &'a Path,
+    /// Program and arguments the rendered command is passed to, from `--prefix-command`
+    prefix: &'a [String],
+    /// Jobs each cargo command is allowed to use
+    cargo_jobs: Option<NonZeroUsize>,
+    /// Features to enable, comma separated
+    features: &'a str,
+    all_features: bool,
+}
+
+/// Every variable a template can use.
+const TEMPLATE_VARIABLES: &[&str] = &[
+    "packages",
+    "excludes",
+    "args",
+    "changed_files",
+    "files",
+    "package_files",
+    "count",
+    "cargo_jobs",
+    "features",
+    "all_features",
+];
+
+/// The variables used by a template.
+fn template_variables(template: &str) -> anyhow::Result<HashSet<String>> {
+    let mut env = Environment::new();
+    env.add_template("cmd", template)?;
+    Ok(env.get_template("cmd")?.undeclared_variables(true))
+}
+
+/// Checks a template only uses supported variables, reporting every unknown one at once.
+fn validate_template(template: &str) -> anyhow::Result<()> {
+    let unknown = template_variables(template)?
+        .into_iter()
+        .filter(|x| !TEMPLATE_VARIABLES.contains(&x.as_str()))
+        .collect::<BTreeSet<_>>();
+    if !unknown.is_empty() {
+        let suggestions = unknown
+            .iter()
+            .filter_map(|x| Some(format!("`{}` for `{}`", closest_variable(x)?, x)))
+            .collect::<Vec<_>>();
+        let suggestions = if suggestions.is_empty() {
+            String::new()
+        } else {
+            format!(" (did you mean {}?)", suggestions.join(", "))
+        };
+        anyhow::bail!(
+            "template uses unknown variables [{}]; supported: [{}]{}",
+            unknown.into_iter().collect::<Vec<_>>().join(", "),
+            TEMPLATE_VARIABLES.join(", "),
+            suggestions
+        );
+    }
+    Ok(())
+}
+
+/// The supported variable most similar to `name`, if any are close enough to be a likely typo.
+fn closest_variable(name: &str) -> Option<&'static str> {
+    TEMPLATE_VARIABLES
+        .iter()
+        .map(|x| (strsim::jaro_winkler(name, x), *x))
+        .filter(|(score, _)| *score > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, x)| x)
+}
+
+/// Renders a template with the variables for the given selection.
+fn render_template(
     template: &str,
-    packages: &Trie<PathBuf, Package>,
+    ctx: &TemplateContext,
     included_packages: &BTreeSet<&str>,
-    args: &[String],
-) -> anyhow::Result<Command> {
+) -> anyhow::Result<String> {
+    validate_template(template)?;
+
     let mut env = Environment::new();
     env.add_template("cmd", template)?;
     let expr = env.get_template("cmd")?;
@@ -110,23 +566,56 @@ fn generate_command(
                 variables.insert(
                     "excludes",
                     Value::from_serialize(generate_exclude_list(
-                        packages.values(),
+                        ctx.packages.values(),
                         included_packages,
                     )),
                 );
             }
             "args" => {
-                variables.insert("args", Value::from_serialize(args));
+                variables.insert("args", Value::from_serialize(ctx.args));
+            }
+            "changed_files" | "files" => {
+                variables.insert(var.as_str(), Value::from_serialize(ctx.changed_files));
+            }
+            "package_files" => {
+                let package_files = included_packages
+                    .iter()
+                    .map(|x| (*x, ctx.package_files.get(x).map_or(&[][..], Vec::as_slice)))
+                    .collect::<BTreeMap<_, _>>();
+                variables.insert("package_files", Value::from_serialize(package_files));
             }
-            s => anyhow::bail!("Unsupported variable `{}`", s),
+            "count" => {
+                variables.insert("count", Value::from(included_packages.len()));
+            }
+            "cargo_jobs" => {
+                variables.insert("cargo_jobs", Value::from_serialize(ctx.cargo_jobs));
+            }
+            "features" => {
+                variables.insert("features", Value::from(ctx.features));
+            }
+            "all_features" => {
+                variables.insert("all_features", Value::from(ctx.all_features));
+            }
+            _ => {}
         }
     }
-    let result = expr.render(&variables)?;
+    Ok(expr.render(&variables)?)
+}
 
-    let parts = shell_words::split(result.as_str())?;
+fn generate_command(
+    template: &str,
+    ctx: &TemplateContext,
+    included_packages: &BTreeSet<&str>,
+) -> anyhow::Result<Command> {
+    let result = render_template(template, ctx, included_packages)?;
+    debug!("Rendered template: {}", result.trim());
+
+    let mut parts = ctx.prefix.to_vec();
+    parts.extend(shell_words::split(result.as_str())?);
     let mut part_iter = parts.into_iter();
     let exe = part_iter.next().context("No program name")?;
     let mut cmd = Command::new(exe);
+    cmd.current_dir(ctx.root);
 
     cmd.args(part_iter)
         .stdout(Stdio::inherit())
@@ -135,84 +624,1079 @@ fn generate_command(
     Ok(cmd)
 }
 
+fn print_json(value: &serde_json::Value, pretty: bool) -> anyhow::Result<()> {
+    if pretty {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        println!("{}", value);
+    }
+    Ok(())
+}
+
+/// Escapes the message of a GitHub Actions workflow command.
+fn escape_workflow_data(data: &str) -> String {
+    data.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escapes a property of a GitHub Actions workflow command, which also can't contain the
+/// separators between properties.
+fn escape_workflow_property(property: &str) -> String {
+    escape_workflow_data(property)
+        .replace(':', "%3A")
+        .replace(',', "%2C")
+}
+
+/// Creates a GitHub Actions workflow command for each selected package explaining why it was
+/// selected. Directly changed packages get a notice on each changed file. GitHub expects paths
+/// relative to the repository, so `repo_prefix` is the root's path inside the repository.
+fn github_annotations(
+    packages: &Trie<PathBuf, Package>,
+    selection: &BTreeMap<PathBuf, Reason>,
+    repo_prefix: &Path,
+) -> Vec<String> {
+    let name = |dir: &PathBuf| {
+        packages
+            .get(dir)
+            .map(|x| x.name.as_str())
+            .unwrap_or_default()
+    };
+    let file = |file: &Path| escape_workflow_property(&repo_prefix.join(file).to_string_lossy());
+    let mut annotations = vec![];
+    for (dir, reason) in selection {
+        match reason {
+            Reason::Changed(files) => {
+                for changed in files {
+                    annotations.push(format!(
+                        "::notice file={}::{}",
+                        file(changed),
+                        escape_workflow_data(&format!("{} selected due to this change", name(dir)))
+                    ));
+                }
+            }
+            Reason::Workspace(changed) => {
+                annotations.push(format!(
+                    "::notice file={}::{}",
+                    file(changed),
+                    escape_workflow_data(&format!(
+                        "{} selected due to this workspace wide change",
+                        name(dir)
+                    ))
+                ));
+            }
+            Reason::Dependency(dependency) => {
+                annotations.push(format!(
+                    "::notice::{}",
+                    escape_workflow_data(&format!(
+                        "{} selected because it depends on {}",
+                        name(dir),
+                        name(dependency)
+                    ))
+                ));
+            }
+        }
+    }
+    annotations
+}
+
+/// Prints a line for each selected package saying why it was selected. Packages selected some
+/// other way, such as `--watch` or `--all-threshold`, don't have a reason in `selection`.
+fn print_explanation(
+    packages: &Trie<PathBuf, Package>,
+    selected: &BTreeSet<PathBuf>,
+    selection: &BTreeMap<PathBuf, Reason>,
+) {
+    let name = |dir: &PathBuf| {
+        packages
+            .get(dir)
+            .map_or_else(|| dir.display().to_string(), |x| x.name.clone())
+    };
+    for dir in selected {
+        match selection.get(dir) {
+            Some(Reason::Changed(files)) => {
+                let files = files
+                    .iter()
+                    .map(|x| x.display().to_string())
+                    .collect::<Vec<_>>();
+                println!("{}: changed ({})", name(dir), files.join(", "));
+            }
+            Some(Reason::Workspace(file)) => {
+                println!("{}: workspace wide change ({})", name(dir), file.display());
+            }
+            Some(Reason::Dependency(dependency)) => {
+                println!("{}: depends on {}", name(dir), name(dependency));
+            }
+            None => println!("{}: selected", name(dir)),
+        }
+    }
+}
+
+/// Prints the dependency graph in Graphviz DOT format, edges point from a package to its
+/// dependencies. Only dependencies of the given kinds are included.
+fn print_dot_graph(
+    packages: &Trie<PathBuf, Package>,
+    selection: &BTreeMap<PathBuf, Reason>,
+    kinds: &BTreeSet<DependencyKind>,
+) {
+    println!("digraph {{");
+    for (dir, package) in packages.iter() {
+        let style = match selection.get(dir) {
+            Some(Reason::Changed(_) | Reason::Workspace(_)) => {
+                " [style=filled, fillcolor=lightcoral]"
+            }
+            Some(Reason::Dependency(_)) => " [style=filled, fillcolor=lightyellow]",
+            None => "",
+        };
+        println!("    {:?}{};", package.name, style);
+    }
+    for (dir, package) in packages.iter() {
+        let dependencies = package
+            .dependencies
+            .iter()
+            .filter(|x| kinds.contains(&x.kind))
+            .filter_map(|x| Some((x.path.as_path(), packages.get(&x.path)?)))
+            .collect::<BTreeMap<_, _>>();
+        for (dependency_dir, dependency) in dependencies {
+            let style = match selection.get(dir) {
+                Some(Reason::Dependency(x)) if x == dependency_dir => " [penwidth=3]",
+                _ => "",
+            };
+            println!("    {:?} -> {:?}{};", package.name, dependency.name, style);
+        }
+    }
+    println!("}}");
+}
+
+/// Renders the command and either prints it or runs it, exiting with the child's status code if
+/// it fails. `use_excludes` picks the form of the built-in template used for `--warmup`.
+fn run_command(
+    template: &str,
+    ctx: &TemplateContext,
+    included_packages: &BTreeSet<&str>,
+    args: &RequiredArgs,
+    use_excludes: bool,
+) -> anyhow::Result<()> {
+    if args.per_package {
+        return run_per_package(template, ctx, included_packages, args, use_excludes);
+    }
+    let mut cmd = generate_command(template, ctx, included_packages)?;
+    exit_on_failure(execute(&mut cmd, args.no_run)?);
+    Ok(())
+}
+
+/// Runs the command once for each package, with up to `--parallel` running at once. By default this
+/// stops at the first failure killing any commands still running, with `--keep-going` every
+/// package is ran. The status of each package is reported at the end.
+fn run_per_package(
+    template: &str,
+    ctx: &TemplateContext,
+    included_packages: &BTreeSet<&str>,
+    args: &RequiredArgs,
+    use_excludes: bool,
+) -> anyhow::Result<()> {
+    if args.warmup && !included_packages.is_empty() {
+        let warmup_args = ["--all-targets".to_string()];
+        let warmup_ctx = TemplateContext {
+            args: &warmup_args,
+            // The warmup runs on its own so can use every core
+            cargo_jobs: args.cargo_jobs,
+            ..*ctx
+        };
+        let template = Preset::Build.template(use_excludes);
+        let mut cmd = generate_command(template, &warmup_ctx, included_packages)?;
+        let status = execute(&mut cmd, args.no_run)?;
+        if !status.success() {
+            error!("Warmup build failed");
+            exit_on_failure(status);
+        }
+    }
+
+    let mut pending = VecDeque::new();
+    for package in included_packages {
+        let single = BTreeSet::from([*package]);
+        let cmd = generate_command(template, ctx, &single)?;
+        if args.no_run {
+            print_command(&cmd);
+        } else {
+            pending.push_back((*package, cmd));
+        }
+    }
+    if args.no_run {
+        return Ok(());
+    }
+
+    // When commands run in parallel their output is piped through so each line can be prefixed
+    // with the package it came from
+    let prefix = args.parallel.get() > 1;
+    let mut forwarders = vec![];
+
+    let mut running: Vec<(&str, Child)> = vec![];
+    let mut finished = vec![];
+    let mut failure = None;
+    while failure.is_none() && (!pending.is_empty() || !running.is_empty()) {
+        while running.len() < args.parallel.get()
+            && let Some((package, mut cmd)) = pending.pop_front()
+        {
+            if prefix {
+                cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+            }
+            let mut child = cmd.spawn()?;
+            if let Some(stdout) = child.stdout.take() {
+                forwarders.push(prefix_output(package, stdout, false));
+            }
+            if let Some(stderr) = child.stderr.take() {
+                forwarders.push(prefix_output(package, stderr, true));
+            }
+            running.push((package, child));
+        }
+
+        let mut any_exited = false;
+        let mut i = 0;
+        while i < running.len() {
+            if let Some(status) = running[i].1.try_wait()? {
+                let (package, _) = running.swap_remove(i);
+                finished.push((package, status));
+                any_exited = true;
+                if !status.success() && !args.keep_going {
+                    failure = Some(status);
+                    break;
+                }
+            } else {
+                i += 1;
+            }
+        }
+        if !any_exited {
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    for (_, child) in &mut running {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    // Killed commands may have left behind processes holding the pipes open so only wait for the
+    // remaining output if everything ran to completion
+    if failure.is_none() {
+        for forwarder in forwarders {
+            let _ = forwarder.join();
+        }
+    }
+
+    for (package, status) in &finished {
+        if status.success() {
+            if !args.quiet {
+                eprintln!("{}: success", package);
+            }
+        } else {
+            eprintln!("{}: failed ({})", package, status);
+        }
+    }
+    let cancelled = running
+        .iter()
+        .map(|(x, _)| x)
+        .chain(pending.iter().map(|(x, _)| x));
+    for package in cancelled {
+        eprintln!("{}: cancelled", package);
+    }
+
+    if let Some(status) = failure {
+        exit_on_failure(status);
+    }
+    if finished.iter().any(|(_, status)| !status.success()) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Forwards each line of a child's output to our stdout or stderr prefixed with the package name.
+fn prefix_output(
+    package: &str,
+    output: impl Read + Send + 'static,
+    to_stderr: bool,
+) -> JoinHandle<()> {
+    let package = package.to_string();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(output);
+        let mut line = vec![];
+        while let Ok(n) = reader.read_until(b'\n', &mut line)
+            && n > 0
+        {
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches(['\r', '\n']);
+            if to_stderr {
+                eprintln!("[{}] {}", package, text);
+            } else {
+                println!("[{}] {}", package, text);
+            }
+            line.clear();
+        }
+    })
+}
+
+/// Runs the command, or just prints it if `no_run` is set.
+fn execute(cmd: &mut Command, no_run: bool) -> anyhow::Result<ExitStatus> {
+    if no_run {
+        print_command(cmd);
+        Ok(ExitStatus::default())
+    } else {
+        debug!("Running `{}`", command_line(cmd));
+        Ok(cmd.status()?)
+    }
+}
+
+/// Exits the process with the same code as the child if it failed.
+fn exit_on_failure(status: ExitStatus) {
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(1));
+    }
+}
+
+/// Formats the command as a shell-escaped command line, in the order it would be executed.
+fn command_line(cmd: &Command) -> String {
+    let mut args = vec![];
+    args.push(cmd.get_program().to_string_lossy());
+    args.extend(cmd.get_args().map(|x| x.to_string_lossy()));
+    shell_words::join(args)
+}
+
+fn print_command(cmd: &Command) {
+    println!("{}", command_line(cmd));
+}
+
+/// Splits the selection into the packages affected by extensions mapped via `--ext-map` and the
+/// packages affected by everything else.
+fn select_mapped_packages(
+    packages: &Trie<PathBuf, Package>,
+    root: &Path,
+    files: &[PathBuf],
+    deleted: &[PathBuf],
+    ext_map: &[(String, Preset)],
+    kinds: &BTreeSet<DependencyKind>,
+    depth: Option<usize>,
+) -> (BTreeSet<PathBuf>, BTreeMap<Preset, BTreeSet<PathBuf>>) {
+    let mut changed_packages = BTreeSet::new();
+    let mut preset_packages: BTreeMap<Preset, BTreeSet<PathBuf>> = BTreeMap::new();
+    for (ext, selected) in
+        dc::select_packages_by_extension(packages, root, files, deleted, kinds, depth)
+    {
+        match ext_map.iter().find(|(x, _)| *x == ext) {
+            Some((_, preset)) => preset_packages.entry(*preset).or_default().extend(selected),
+            None => changed_packages.extend(selected),
+        }
+    }
+    (changed_packages, preset_packages)
+}
+
+/// Whether the output could depend on the packages in the workspace even when none are selected,
+/// for instance by listing the excluded packages. The packages named by `--watch` are also checked
+/// to exist so a typo is reported whether or not anything changed.
+fn needs_workspace(args: &RunCommand, config: &Config) -> anyhow::Result<bool> {
+    let required = args.required_args();
+    if required.changed_within_package.is_some()
+        || !required.watch.is_empty()
+        || required.verify_dependencies_resolve
+        || required.select_reverse_only_published
+        || required.exclude_mode
+        || required.graph.is_some()
+        || matches!(
+            required.format,
+            OutputFormat::Json | OutputFormat::CargoExclude
+        )
+        || required.output_template.is_some()
+    {
+        return Ok(true);
+    }
+    match args.command(config, required.exclude_mode) {
+        Some(template) => Ok(template_variables(&template)?.contains("excludes")),
+        None => Ok(false),
+    }
+}
+
 fn main() -> anyhow::Result<()> {
     let args = RunCommand::parse();
 
-    let root = args.required_args().path();
+    let level = match args.required_args().verbose {
+        _ if args.required_args().quiet => LevelFilter::Error,
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .format_timestamp(None)
+        .format_target(false)
+        .parse_default_env()
+        .init();
 
-    let considered_files = repository::get_changed_source_files(&root)?;
+    // `--input` can be anywhere in the workspace, everything works relative to its root
+    let input = args.required_args().path();
+    let root = args.required_args().root()?;
+    // Errors name the workspace root, so point back at the input if it was somewhere else in it
+    let with_input = |e: anyhow::Error| {
+        if input == root {
+            e
+        } else {
+            e.context(format!(
+                "in the workspace at {} found from {}",
+                root.display(),
+                input.display()
+            ))
+        }
+    };
 
-    // Now from these files we want to create a list of projects in the workspace we should run
-    // tests on. This is done via two easy checks:
-    //
-    // 1. If a project has a file that changed. Rerun it
-    // 2. If a project has a dependency in the workspace that changed. Run it.
-    //
-    // We can skip dependency tree creation if 1. covers all projects. Once we get all the ones in
-    // 1. we can also do some early exiting of the dependency tree resolution to save a bit of
-    // effort!
+    let config = Config::load(&root)?;
 
-    let packages = cargo::find_packages(&root)?;
+    // Check every template up front so a typo is reported before waiting on git and cargo, or
+    // after other commands have ran
+    let package_templates = config.package_templates()?;
+    for template in args
+        .command(&config, args.required_args().exclude_mode)
+        .iter()
+        .map(|x| x.as_ref())
+        .chain(args.required_args().output_template.as_deref())
+        .chain(package_templates.iter().map(|(_, x)| *x))
+    {
+        validate_template(template)?;
+    }
 
-    let mut changed_packages = BTreeSet::new();
+    let opts = args.required_args().selection_options(&config)?;
+
+    let considered_files = dc::changed_files(&root, &opts).map_err(with_input)?;
+
+    debug!("Changed files: {:?}", considered_files);
+
+    // With nothing changed nothing can be selected, so unless the output needs the packages in
+    // the workspace there's no point waiting on cargo metadata
+    let packages = if considered_files.is_empty() && !needs_workspace(&args, &config)? {
+        info!("No files changed, skipping cargo metadata");
+        Trie::new()
+    } else {
+        let packages = opts.packages(&root).map_err(with_input)?;
+        info!("Found {} packages in the workspace", packages.len());
+        packages
+    };
+    let deleted = dc::deleted_packages(
+        &packages,
+        &root,
+        &considered_files,
+        opts.change_source(&root).as_ref(),
+    )?;
 
-    let mut end_package_names = BTreeSet::new();
+    if args.required_args().verify_dependencies_resolve {
+        let unresolved = dc::unresolved_dependencies(&packages);
+        for (package, path) in &unresolved {
+            error!(
+                "`{}` depends on {} which isn't a package in the workspace",
+                package,
+                path.display()
+            );
+        }
+        if !unresolved.is_empty() {
+            anyhow::bail!(
+                "{} dependencies don't resolve to a package",
+                unresolved.len()
+            );
+        }
+    }
 
-    for file in &considered_files {
-        if let Some(package) = packages.get_ancestor_value(&root.join(file)) {
-            changed_packages.insert(root.join(file));
-            end_package_names.insert(package.name.as_str());
+    if let Some(name) = args.required_args().changed_within_package.as_ref() {
+        let attributed = dc::attribute_files(&packages, &root, &considered_files, &deleted);
+        let package_dir = packages
+            .iter()
+            .find(|(_, package)| package.name == *name)
+            .map(|(dir, _)| dir)
+            .with_context(|| format!("no package named `{}` in the workspace", name))?;
+        if let Some(files) = attributed.get(package_dir) {
+            for file in files {
+                let file = root.join(file);
+                let relative = file.strip_prefix(package_dir).unwrap_or(&file);
+                println!("{}", relative.display());
+            }
         }
+        return Ok(());
     }
 
-    let mut changed_packages_previous = 0;
+    if args.required_args().select_reverse_only_published {
+        let mut published = dc::select_published(
+            &packages,
+            &root,
+            &considered_files,
+            &deleted,
+            &opts.kinds,
+            opts.depth,
+        );
+        opts.package_filter.apply(&packages, &mut published);
+        let names = dc::package_names(&packages, &published);
+        let output = serde_json::json!({
+            "packages": names,
+            "excludes": generate_exclude_list(packages.values(), &names),
+        });
+        print_json(&output, args.required_args().json_pretty)?;
+        return Ok(());
+    }
+
+    let ext_map = &args.required_args().ext_map;
+    let kinds = &opts.kinds;
+    let depth = opts.depth;
+
+    let (mut changed_packages, mut preset_packages) = if ext_map.is_empty() {
+        let selected =
+            dc::select_packages(&packages, &root, &considered_files, &deleted, kinds, depth);
+        (selected, BTreeMap::new())
+    } else {
+        select_mapped_packages(
+            &packages,
+            &root,
+            &considered_files,
+            &deleted,
+            ext_map,
+            kinds,
+            depth,
+        )
+    };
 
-    while changed_packages_previous != changed_packages.len() {
-        changed_packages_previous = changed_packages.len();
+    changed_packages.extend(dc::select_renames(
+        &packages,
+        &root,
+        &considered_files,
+        &opts,
+    )?);
+    changed_packages.extend(dc::select_watched(
+        &packages,
+        &considered_files,
+        &args.required_args().watch,
+        kinds,
+        depth,
+    )?);
 
-        for (key, val) in packages.iter() {
-            if val
-                .dependencies
+    opts.package_filter.apply(&packages, &mut changed_packages);
+    for selected in preset_packages.values_mut() {
+        opts.package_filter.apply(&packages, selected);
+    }
+
+    // Whether the built-in commands exclude the packages that weren't selected rather than listing
+    // the selected ones
+    let mut use_excludes = args.required_args().exclude_mode;
+    if let Some(threshold) = args.required_args().all_threshold
+        && changed_packages.len() > threshold
+    {
+        // Past the threshold select everything, the built-in commands switch to excluding
+        // packages so they run on the whole workspace rather than listing every package
+        changed_packages = packages.keys().cloned().collect();
+        opts.package_filter.apply(&packages, &mut changed_packages);
+        use_excludes = true;
+    }
+
+    let mut end_package_names = dc::package_names(&packages, &changed_packages);
+
+    if args.required_args().count_only {
+        println!("{}", end_package_names.len());
+        return Ok(());
+    }
+
+    if args.required_args().explain || args.required_args().graph.is_some() {
+        let mut selection =
+            dc::explain_selection(&packages, &root, &considered_files, &deleted, kinds, depth);
+        selection.retain(|dir, _| changed_packages.contains(dir));
+        if args.required_args().explain {
+            print_explanation(&packages, &changed_packages, &selection);
+        } else {
+            print_dot_graph(&packages, &selection, kinds);
+        }
+        return Ok(());
+    }
+
+    if args.required_args().null_on_empty
+        && end_package_names.is_empty()
+        && preset_packages.values().all(BTreeSet::is_empty)
+    {
+        info!("No packages have changed");
+        return Ok(());
+    }
+
+    let command = args.command(&config, use_excludes);
+
+    let packages_only = args.required_args().packages_only;
+    if packages_only && matches!(&args, RunCommand::Run(a) if a.command.is_some()) {
+        anyhow::bail!("--packages-only can't be used with --command");
+    }
+
+    if packages_only || command.is_none() || args.required_args().no_run {
+        match args.required_args().format {
+            OutputFormat::Text if packages_only => {
+                // Past the threshold the excludes are shorter, same as the built-in commands
+                let output = if use_excludes {
+                    exclude_args(packages.values(), &end_package_names)
+                } else {
+                    end_package_names.iter().flat_map(|x| ["-p", x]).collect()
+                };
+                if !output.is_empty() {
+                    println!("{}", shell_words::join(output));
+                }
+                return Ok(());
+            }
+            OutputFormat::Text => {}
+            OutputFormat::Lines => {
+                for package in &end_package_names {
+                    println!("{}", package);
+                }
+                return Ok(());
+            }
+            OutputFormat::Json => {
+                let output = serde_json::json!({
+                    "packages": end_package_names,
+                    "excludes": generate_exclude_list(packages.values(), &end_package_names),
+                });
+                print_json(&output, args.required_args().json_pretty)?;
+                return Ok(());
+            }
+            OutputFormat::CargoExclude => {
+                // An empty string would run everything, so selecting nothing has to be an error
+                if end_package_names.is_empty() {
+                    anyhow::bail!(
+                        "no packages selected, cargo can't be told to exclude all of them"
+                    );
+                }
+                let output = exclude_args(packages.values(), &end_package_names);
+                if !output.is_empty() {
+                    println!("{}", shell_words::join(output));
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    if args.required_args().github_annotations {
+        let mut selection =
+            dc::explain_selection(&packages, &root, &considered_files, &deleted, kinds, depth);
+        selection.retain(|dir, _| changed_packages.contains(dir));
+        let repo_prefix = dc::repository::root_in_repository(&root)?;
+        for annotation in github_annotations(&packages, &selection, &repo_prefix) {
+            println!("{}", annotation);
+        }
+    }
+
+    let prefix = match args.required_args().prefix_command.as_ref() {
+        Some(prefix) => shell_words::split(prefix).context("invalid --prefix-command")?,
+        None => vec![],
+    };
+
+    let features = args.required_args().features.join(",");
+
+    let package_files = dc::attribute_files(&packages, &root, &considered_files, &deleted)
+        .into_iter()
+        .filter_map(|(dir, files)| Some((packages.get(&dir)?.name.as_str(), files)))
+        .collect();
+
+    let ctx = TemplateContext {
+        root: &root,
+        packages: &packages,
+        changed_files: &considered_files,
+        package_files: &package_files,
+        args: &args.required_args().args,
+        prefix: &prefix,
+        cargo_jobs: args.required_args().cargo_jobs(),
+        features: &features,
+        all_features: args.required_args().all_features,
+    };
+
+    if let Some(template) = args.required_args().output_template.as_ref()
+        && (command.is_none() || args.required_args().no_run)
+    {
+        println!("{}", render_template(template, &ctx, &end_package_names)?);
+        return Ok(());
+    }
+
+    for (preset, selected) in &preset_packages {
+        if !selected.is_empty() {
+            let names = dc::package_names(&packages, selected);
+            let template = preset.template(use_excludes);
+            run_command(template, &ctx, &names, args.required_args(), use_excludes)?;
+        }
+    }
+
+    // Packages with their own template in the config file are taken out of the main command. With
+    // no command only the selection is printed, so they're left in it
+    let mut template_packages: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    if command.is_some() {
+        end_package_names.retain(|name| {
+            match package_templates
                 .iter()
-                .any(|x| changed_packages.contains(x))
+                .find(|(glob, _)| glob.is_match(name))
             {
-                if let Some(package) = packages.get_ancestor_value(&root.join(key)) {
-                    changed_packages.insert(root.join(key));
-                    end_package_names.insert(package.name.as_str());
+                Some((_, template)) => {
+                    template_packages.entry(template).or_default().insert(name);
+                    false
                 }
+                None => true,
             }
-        }
+        });
+    }
+    for (template, names) in &template_packages {
+        run_command(template, &ctx, names, args.required_args(), use_excludes)?;
     }
 
-    //let exclude = generate_exclude_list(packages.values(), &end_package_names);
+    // If every change was routed to a preset or package template don't run the main command with
+    // nothing selected
+    if (!preset_packages.is_empty() || !template_packages.is_empty())
+        && end_package_names.is_empty()
+    {
+        return Ok(());
+    }
 
-    if let Some(cmd) = args.command() {
-        let mut cmd = generate_command(
+    if let Some(cmd) = command {
+        run_command(
             &cmd,
-            &packages,
+            &ctx,
             &end_package_names,
-            &args.required_args().args,
+            args.required_args(),
+            use_excludes,
         )?;
-        if args.required_args().no_run {
-            let mut args = vec![];
-            args.push(cmd.get_program().to_string_lossy());
-            args.extend(cmd.get_args().into_iter().map(|x| x.to_string_lossy()));
-            println!("{}", shell_words::join(args));
-        } else {
-            cmd.status()?;
-        }
-    } else if !changed_packages.is_empty() {
+    } else if !end_package_names.is_empty() {
         println!(
             "Changed packages end: `-p {}`",
             end_package_names
                 .iter()
-                .map(|x| *x)
+                .copied()
                 .collect::<Vec<_>>()
                 .join(" -p ")
         );
-    } else {
+    } else if !args.required_args().quiet {
         println!("No packages have changed");
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixture::Fixture;
+
+    /// Builds a workspace of packages with no dependencies, keyed by a made up directory.
+    fn workspace(names: &[&str]) -> Trie<PathBuf, Package> {
+        let mut packages = Trie::new();
+        for name in names {
+            let dir = Path::new("/ws").join(name);
+            let package = Package {
+                name: name.to_string(),
+                manifest: dir.join("Cargo.toml"),
+                ..Default::default()
+            };
+            packages.insert(dir, package);
+        }
+        packages
+    }
+
+    /// Template context for the packages with nothing else set.
+    fn context<'a>(
+        packages: &'a Trie<PathBuf, Package>,
+        package_files: &'a BTreeMap<&'a str, Vec<PathBuf>>,
+    ) -> TemplateContext<'a> {
+        TemplateContext {
+            packages,
+            changed_files: &[],
+            package_files,
+            args: &[],
+            root: Path::new("/ws"),
+            prefix: &[],
+            cargo_jobs: None,
+            features: "",
+            all_features: false,
+        }
+    }
+
+    /// Renders the template into the program and arguments that would be ran.
+    fn argv_in(template: &str, ctx: &TemplateContext, included: &[&str]) -> Vec<String> {
+        let included = included.iter().copied().collect();
+        let cmd = generate_command(template, ctx, &included).unwrap();
+        std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|x| x.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn argv(template: &str, packages: &Trie<PathBuf, Package>, included: &[&str]) -> Vec<String> {
+        let package_files = BTreeMap::new();
+        argv_in(template, &context(packages, &package_files), included)
+    }
+
+    #[test]
+    fn built_in_templates_run_their_subcommand() {
+        let config = Config::default();
+        let packages = workspace(&["a"]);
+        for (subcommand, expected) in [
+            ("test", "test"),
+            ("nextest", "nextest"),
+            ("build", "build"),
+            ("bench", "bench"),
+        ] {
+            let args = RunCommand::parse_from(["dc", subcommand]);
+            let template = args.command(&config, false).unwrap();
+            assert_eq!(
+                argv(&template, &packages, &["a"]),
+                ["cargo", expected, "-p", "a"]
+            );
+        }
+    }
+
+    #[test]
+    fn exclude_mode_excludes_exactly_the_unselected_packages() {
+        let config = Config::default();
+        let packages = workspace(&["a", "b", "c", "d"]);
+        for subcommand in ["test", "nextest", "build", "bench"] {
+            let args = RunCommand::parse_from(["dc", subcommand, "--exclude-mode"]);
+            let template = args.command(&config, true).unwrap();
+            assert_eq!(
+                argv(&template, &packages, &["b", "d"])[1..],
+                [
+                    subcommand,
+                    "--workspace",
+                    "--exclude",
+                    "a",
+                    "--exclude",
+                    "c"
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn parallel_and_cargo_jobs_can_be_set_together() {
+        let args = RunCommand::parse_from([
+            "dc",
+            "test",
+            "--per-package",
+            "--parallel",
+            "2",
+            "--cargo-jobs",
+            "4",
+        ]);
+        let required = args.required_args();
+        assert_eq!(required.parallel.get(), 2);
+        assert_eq!(required.cargo_jobs(), NonZeroUsize::new(4));
+
+        let packages = workspace(&["a"]);
+        let package_files = BTreeMap::new();
+        let ctx = TemplateContext {
+            cargo_jobs: required.cargo_jobs(),
+            ..context(&packages, &package_files)
+        };
+        let config = Config::default();
+        let template = args.command(&config, false).unwrap();
+        assert_eq!(
+            argv_in(&template, &ctx, &["a"]),
+            ["cargo", "test", "-p", "a", "--jobs", "4"]
+        );
+    }
+
+    #[test]
+    fn parallel_commands_split_the_cores_by_default() {
+        let args = RunCommand::parse_from(["dc", "test", "--per-package", "--parallel", "2"]);
+        let jobs = args.required_args().cargo_jobs().unwrap().get();
+        let cores = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        assert!(jobs * 2 <= cores.max(2));
+    }
+
+    #[test]
+    fn unknown_template_variables_list_the_supported_ones() {
+        let error =
+            validate_template("cargo test {% for pkg in packges %} -p {{ pkg }}{% endfor %}")
+                .unwrap_err()
+                .to_string();
+        assert_eq!(
+            error,
+            format!(
+                "template uses unknown variables [packges]; supported: [{}] (did you mean `packages` for `packges`?)",
+                TEMPLATE_VARIABLES.join(", ")
+            )
+        );
+        assert!(validate_template(CARGO_TEST_TEMPLATE).is_ok());
+    }
+
+    #[test]
+    fn run_templates_can_use_the_changed_files_of_each_package() {
+        let template = "fmt-check {% for pkg, files in package_files|items %} --package {{ pkg }} {% for file in files %} '{{ file }}' {% endfor %}{% endfor %} -- {% for file in changed_files %} '{{ file }}' {% endfor %}";
+        let args = RunCommand::parse_from(["dc", "run", "--command", template]);
+        let config = Config::default();
+        let template = args.command(&config, false).unwrap();
+
+        let packages = workspace(&["a", "b", "c"]);
+        let changed_files = [
+            PathBuf::from("a/src/lib.rs"),
+            PathBuf::from("b/src/my file.rs"),
+            PathBuf::from("c/src/lib.rs"),
+        ];
+        let package_files = BTreeMap::from([
+            ("a", vec![changed_files[0].clone()]),
+            ("b", vec![changed_files[1].clone()]),
+            ("c", vec![changed_files[2].clone()]),
+        ]);
+        let ctx = TemplateContext {
+            changed_files: &changed_files,
+            ..context(&packages, &package_files)
+        };
+        assert_eq!(
+            argv_in(&template, &ctx, &["a", "b"]),
+            [
+                "fmt-check",
+                "--package",
+                "a",
+                "a/src/lib.rs",
+                "--package",
+                "b",
+                "b/src/my file.rs",
+                "--",
+                "a/src/lib.rs",
+                "b/src/my file.rs",
+                "c/src/lib.rs",
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_input_selects_the_same_as_the_root() {
+        let fixture = Fixture::workspace(&[
+            ("crates/a", &["crates/b"]),
+            ("crates/b", &[]),
+            ("crates/c", &[]),
+        ]);
+        fixture.touch("crates/b/src/lib.rs");
+        fixture.commit("Change b");
+
+        let config = Config::default();
+        let select = |input: &Path| {
+            let args = RunCommand::parse_from([
+                Path::new("dc"),
+                Path::new("test"),
+                Path::new("--input"),
+                input,
+            ]);
+            let root = args.required_args().root().unwrap();
+            let opts = args.required_args().selection_options(&config).unwrap();
+            (root.clone(), dc::affected_packages(&root, &opts).unwrap())
+        };
+        let (root, selected) = select(&fixture.root);
+        assert_eq!(root, fixture.root);
+        assert_eq!(selected, BTreeSet::from(["a".to_string(), "b".to_string()]));
+        assert_eq!(select(&fixture.dir("crates/c/src")), (root, selected));
+    }
+
+    #[test]
+    fn watching_a_package_needs_the_workspace() {
+        let config = Config::default();
+        let args = RunCommand::parse_from(["dc", "test", "--no-run"]);
+        assert!(!needs_workspace(&args, &config).unwrap());
+        let args = RunCommand::parse_from(["dc", "test", "--no-run", "--watch", "vendor=a"]);
+        assert!(needs_workspace(&args, &config).unwrap());
+    }
+
+    #[test]
+    fn mapped_extensions_are_considered() {
+        let config = Config::default();
+        let proto = Path::new("a/schema.proto");
+        let args = RunCommand::parse_from(["dc", "test"]);
+        assert!(
+            !args
+                .required_args()
+                .file_filter(&config)
+                .unwrap()
+                .is_considered(proto)
+        );
+        for args in [
+            vec!["dc", "test", "--ext-map", "proto=build"],
+            vec![
+                "dc",
+                "test",
+                "--ext-map",
+                ".PROTO=build",
+                "--only-ext",
+                "--ext",
+                "rs",
+            ],
+        ] {
+            let args = RunCommand::parse_from(args);
+            assert!(
+                args.required_args()
+                    .file_filter(&config)
+                    .unwrap()
+                    .is_considered(proto)
+            );
+        }
+    }
+
+    #[test]
+    fn built_in_templates_pass_features() {
+        let packages = workspace(&["a", "b"]);
+        let package_files = BTreeMap::new();
+        for preset in [Preset::Test, Preset::Nextest, Preset::Build, Preset::Bench] {
+            for exclude_mode in [false, true] {
+                let template = preset.template(exclude_mode);
+                let ctx = TemplateContext {
+                    features: "foo,bar",
+                    ..context(&packages, &package_files)
+                };
+                let argv = argv_in(template, &ctx, &["a"]);
+                assert!(argv.ends_with(&["--features".into(), "foo,bar".into()]));
+                let ctx = TemplateContext {
+                    all_features: true,
+                    ..context(&packages, &package_files)
+                };
+                let argv = argv_in(template, &ctx, &["a"]);
+                assert!(argv.ends_with(&["--all-features".into()]));
+            }
+        }
+    }
+
+    #[test]
+    fn annotations_are_relative_to_the_repository() {
+        let fixture = Fixture::new();
+        fixture.add_crate("rust/a", &[]);
+        fixture.write("rust/Cargo.toml", "[workspace]\nmembers = [\"a\"]\n");
+        fixture.commit("Initial commit");
+        let repo_prefix = dc::repository::root_in_repository(&fixture.dir("rust")).unwrap();
+        assert_eq!(repo_prefix, Path::new("rust"));
+
+        let packages = workspace(&["a", "b"]);
+        let selection = BTreeMap::from([
+            (
+                PathBuf::from("/ws/a"),
+                Reason::Changed(vec!["a/src/1,000%:x.rs".into()]),
+            ),
+            (
+                PathBuf::from("/ws/b"),
+                Reason::Dependency(PathBuf::from("/ws/a")),
+            ),
+        ]);
+        assert_eq!(
+            github_annotations(&packages, &selection, &repo_prefix),
+            [
+                "::notice file=rust/a/src/1%2C000%25%3Ax.rs::a selected due to this change",
+                "::notice::b selected because it depends on a",
+            ]
+        );
+    }
+
+    #[test]
+    fn crossing_the_threshold_keeps_config_templates() {
+        let config: Config =
+            toml::from_str("[templates]\ntest = \"cargo xtask test {{ packages }}\"").unwrap();
+        let args = RunCommand::parse_from(["dc", "test", "--all-threshold", "1"]);
+        assert_eq!(
+            args.command(&config, true).unwrap(),
+            "cargo xtask test {{ packages }}"
+        );
+        assert_eq!(
+            args.command(&Config::default(), true).unwrap(),
+            CARGO_TEST_EXCLUDE_TEMPLATE
+        );
+
+        let args = RunCommand::parse_from(["dc", "test", "--exclude-mode"]);
+        assert_eq!(
+            args.command(&config, true).unwrap(),
+            CARGO_TEST_EXCLUDE_TEMPLATE
+        );
+    }
+}
@@ -1,51 +1,427 @@
-use git2::{DiffOptions, Repository};
+use crate::build_globset;
+use anyhow::Context;
+use git2::{Commit, Delta, DiffFindOptions, DiffOptions, Repository, Tree};
+use globset::GlobSet;
+use std::collections::BTreeSet;
+use std::fs;
 use std::path::{Path, PathBuf};
 
-pub fn is_considered(path: &Path) -> bool {
-    let ext = match path.extension().and_then(|e| e.to_str()) {
-        Some(e) => e.to_ascii_lowercase(),
-        None => return path.is_dir(),
-    };
-    matches!(
-        ext.as_str(),
-        "rs" | "c" | "cpp" | "h" | "hpp" | "cc" | "cxx" | "toml" | "pb"
-    )
+/// A source of changed files, paths are returned relative to the root of the project.
+pub trait ChangeSource {
+    fn changed_files(&self) -> anyhow::Result<Vec<PathBuf>>;
+
+    /// Contents of the file at `path` (relative to the root) before the changes, `None` if it
+    /// didn't exist or the source can't tell.
+    fn previous_contents(&self, _path: &Path) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Whether the file at `path` (relative to the root) no longer exists after the changes,
+    /// `false` if the source can't tell.
+    fn is_deleted(&self, _path: &Path) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Finds the files changed in the last git commit, or since a base revision if one is given.
+#[derive(Debug, Clone)]
+pub struct GitChangeSource {
+    root: PathBuf,
+    base: Option<String>,
+    range: Option<(String, String)>,
+    all_parents: bool,
+    filter: FileFilter,
 }
 
-pub fn get_changed_source_files(root: &Path) -> anyhow::Result<Vec<PathBuf>> {
-    let repo = Repository::open(root)?;
+impl GitChangeSource {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            base: None,
+            range: None,
+            all_parents: false,
+            filter: FileFilter::default(),
+        }
+    }
 
-    // Get HEAD commit
-    let head = repo.head()?;
-    let commit = head.peel_to_commit()?;
+    /// Diff against this revision instead of the parent of HEAD.
+    pub fn with_base(mut self, base: Option<String>) -> Self {
+        self.base = base;
+        self
+    }
 
-    // Last commit should have at least one parent
-    let parent = commit.parent(0)?;
+    /// Diff the tree of the start revision against the end one instead of looking at HEAD.
+    pub fn with_range(mut self, range: Option<(String, String)>) -> Self {
+        self.range = range;
+        self
+    }
 
-    // Get trees
-    let commit_tree = commit.tree()?;
-    let parent_tree = parent.tree()?;
+    /// For merge commits diff against every parent and union the results instead of diffing
+    /// against the merge base of the parents.
+    pub fn with_all_parents(mut self, all_parents: bool) -> Self {
+        self.all_parents = all_parents;
+        self
+    }
 
-    let mut diff_opt = DiffOptions::new();
+    /// Set the filter used to decide which changed files are returned.
+    pub fn with_filter(mut self, filter: FileFilter) -> Self {
+        self.filter = filter;
+        self
+    }
 
-    // Diff parent -> commit
-    let diff =
-        repo.diff_tree_to_tree(Some(&parent_tree), Some(&commit_tree), Some(&mut diff_opt))?;
+    /// Opens the repository containing the root, which may be further up the tree.
+    fn open(&self) -> anyhow::Result<Repository> {
+        Repository::discover(&self.root).with_context(|| {
+            format!(
+                "no git repository found at {}; delta_cmd needs git history to diff against",
+                self.root.display()
+            )
+        })
+    }
 
-    let mut considered_files = vec![];
-    diff.foreach(
-        &mut |delta, _| {
-            if let Some(path) = delta.new_file().path().or_else(|| delta.old_file().path()) {
-                if is_considered(&root.join(path)) {
-                    considered_files.push(path.to_path_buf());
-                }
+    /// Where the root is inside the repository, git works with paths relative to the top of the
+    /// repository but everything else is relative to the root.
+    fn root_in_repo(&self, repo: &Repository) -> anyhow::Result<PathBuf> {
+        let workdir = repo
+            .workdir()
+            .context("bare repositories aren't supported")?;
+        let root = fs::canonicalize(&self.root)?;
+        Ok(root.strip_prefix(fs::canonicalize(workdir)?)?.to_path_buf())
+    }
+
+    /// The commit whose changes are looked at, the end of the range if there is one otherwise
+    /// HEAD.
+    fn target_commit<'r>(&self, repo: &'r Repository) -> anyhow::Result<Commit<'r>> {
+        match self.range.as_ref() {
+            Some((_, end)) => resolve_range_end(repo, end, "end"),
+            None => Ok(repo.head()?.peel_to_commit()?),
+        }
+    }
+
+    /// The trees to diff the target commit against, `None` stands for an empty tree.
+    fn base_trees<'r>(
+        &self,
+        repo: &'r Repository,
+        commit: &Commit<'r>,
+    ) -> anyhow::Result<Vec<Option<Tree<'r>>>> {
+        let trees = if let Some((start, _)) = self.range.as_ref() {
+            vec![Some(resolve_range_end(repo, start, "start")?.tree()?)]
+        } else if let Some(base) = self.base.as_ref() {
+            vec![Some(repo.revparse_single(base)?.peel_to_tree()?)]
+        } else {
+            match commit.parent_count() {
+                0 => vec![None],
+                1 => vec![Some(commit.parent(0)?.tree()?)],
+                _ if !self.all_parents => vec![merge_base_tree(repo, commit)?],
+                _ => commit
+                    .parents()
+                    .map(|x| x.tree().map(Some))
+                    .collect::<Result<Vec<_>, _>>()?,
             }
-            true
-        },
-        None,
-        None,
-        None,
-    )?;
-
-    Ok(considered_files)
+        };
+        Ok(trees)
+    }
+}
+
+impl ChangeSource for GitChangeSource {
+    /// Diffs HEAD against the trees picked by the following rules:
+    ///
+    /// * If a range is given the start is diffed against the end, HEAD isn't used
+    /// * If a base revision is given only that is diffed against
+    /// * The initial commit has no parents so it's diffed against an empty tree, making every file
+    ///   in it count as changed
+    /// * Normal commits are diffed against their parent
+    /// * Merge commits are diffed against the merge base of their parents, so the changes from
+    ///   every side of the merge are seen. With `all_parents` set they're diffed against each
+    ///   parent and the results unioned instead
+    fn changed_files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let repo = self.open()?;
+        let prefix = self.root_in_repo(&repo)?;
+
+        let commit = self.target_commit(&repo)?;
+
+        // Get trees
+        let commit_tree = commit.tree()?;
+        let parent_trees = self.base_trees(&repo, &commit)?;
+
+        let mut considered_files = BTreeSet::new();
+        for parent_tree in &parent_trees {
+            let mut diff_opt = DiffOptions::new();
+
+            // Diff parent -> commit
+            let mut diff = repo.diff_tree_to_tree(
+                parent_tree.as_ref(),
+                Some(&commit_tree),
+                Some(&mut diff_opt),
+            )?;
+
+            // Without this a moved file shows up as an unrelated add and delete
+            diff.find_similar(Some(DiffFindOptions::new().renames(true).copies(true)))?;
+
+            diff.foreach(
+                &mut |delta, _| {
+                    // A rename changes both the package it left and the one it moved to
+                    let paths = if delta.status() == Delta::Renamed {
+                        vec![delta.old_file().path(), delta.new_file().path()]
+                    } else {
+                        vec![delta.new_file().path().or_else(|| delta.old_file().path())]
+                    };
+                    // Changes outside of the root are ignored
+                    let paths = paths
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|x| x.strip_prefix(&prefix).ok());
+                    for path in paths {
+                        if self.filter.is_considered_in(&self.root, path) {
+                            considered_files.insert(path.to_path_buf());
+                        }
+                    }
+                    true
+                },
+                None,
+                None,
+                None,
+            )?;
+        }
+
+        Ok(considered_files.into_iter().collect())
+    }
+
+    /// Reads the file from the first tree HEAD is diffed against.
+    fn previous_contents(&self, path: &Path) -> anyhow::Result<Option<String>> {
+        let repo = self.open()?;
+        let path = self.root_in_repo(&repo)?.join(path);
+        let commit = self.target_commit(&repo)?;
+        let Some(tree) = self
+            .base_trees(&repo, &commit)?
+            .into_iter()
+            .flatten()
+            .next()
+        else {
+            return Ok(None);
+        };
+        let entry = match tree.get_path(&path) {
+            Ok(entry) => entry,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let object = entry.to_object(&repo)?;
+        Ok(object
+            .as_blob()
+            .and_then(|x| std::str::from_utf8(x.content()).ok())
+            .map(ToString::to_string))
+    }
+
+    /// Looks the file up in the tree of the commit whose changes are looked at, so this doesn't
+    /// depend on what's checked out.
+    fn is_deleted(&self, path: &Path) -> anyhow::Result<bool> {
+        let repo = self.open()?;
+        let path = self.root_in_repo(&repo)?.join(path);
+        match self.target_commit(&repo)?.tree()?.get_path(&path) {
+            Ok(_) => Ok(false),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(true),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The tree of the merge base of a merge commit's parents, or `None` (an empty tree) if their
+/// histories are unrelated.
+fn merge_base_tree<'r>(
+    repo: &'r Repository,
+    commit: &Commit<'r>,
+) -> anyhow::Result<Option<Tree<'r>>> {
+    let parents = commit.parent_ids().collect::<Vec<_>>();
+    match repo.merge_base_many(&parents) {
+        Ok(base) => Ok(Some(repo.find_commit(base)?.tree()?)),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn resolve_range_end<'r>(
+    repo: &'r Repository,
+    rev: &str,
+    which: &str,
+) -> anyhow::Result<Commit<'r>> {
+    repo.revparse_single(rev)
+        .and_then(|x| x.peel_to_commit())
+        .with_context(|| format!("range {} `{}` isn't a commit in the repository", which, rev))
+}
+
+/// File extensions which are considered source files by default.
+pub const DEFAULT_EXTENSIONS: &[&str] = &["rs", "c", "cpp", "h", "hpp", "cc", "cxx", "toml", "pb"];
+
+/// Decides which changed files are considered when selecting packages.
+#[derive(Debug, Clone)]
+pub struct FileFilter {
+    extensions: BTreeSet<String>,
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl Default for FileFilter {
+    fn default() -> Self {
+        Self::new(DEFAULT_EXTENSIONS)
+    }
+}
+
+impl FileFilter {
+    /// Create a filter which considers files with any of the given extensions. Extensions are
+    /// matched case-insensitively and a leading `.` is ignored.
+    pub fn new(extensions: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        Self {
+            extensions: extensions
+                .into_iter()
+                .map(|x| x.as_ref().trim_start_matches('.').to_ascii_lowercase())
+                .collect(),
+            include: None,
+            exclude: None,
+        }
+    }
+
+    /// Add more extensions to the ones already considered.
+    pub fn extend(&mut self, extensions: impl IntoIterator<Item = impl AsRef<str>>) {
+        self.extensions.extend(Self::new(extensions).extensions);
+    }
+
+    /// Set globs matched against paths relative to the root. Files matching an include glob are
+    /// considered whatever their extension, and files matching an exclude glob are never
+    /// considered even if they also match an include glob.
+    pub fn set_globs(&mut self, include: &[String], exclude: &[String]) -> anyhow::Result<()> {
+        self.include = build_globset(include)?;
+        self.exclude = build_globset(exclude)?;
+        Ok(())
+    }
+
+    /// Like [`Self::is_considered`] but also applies the include and exclude globs, `path` is
+    /// relative to `root`.
+    pub fn is_considered_in(&self, root: &Path, path: &Path) -> bool {
+        if self.exclude.as_ref().is_some_and(|x| x.is_match(path)) {
+            return false;
+        }
+        if self.include.as_ref().is_some_and(|x| x.is_match(path)) {
+            return true;
+        }
+        self.is_considered(&root.join(path))
+    }
+
+    pub fn is_considered(&self, path: &Path) -> bool {
+        // Lockfile changes can affect any package so are always considered
+        if path.file_name().is_some_and(|x| x == "Cargo.lock") {
+            return true;
+        }
+        let ext = match path.extension().and_then(|e| e.to_str()) {
+            Some(e) => e.to_ascii_lowercase(),
+            None => return path.is_dir(),
+        };
+        self.extensions.contains(&ext)
+    }
+}
+
+pub fn get_changed_source_files(root: &Path, base: Option<&str>) -> anyhow::Result<Vec<PathBuf>> {
+    GitChangeSource::new(root)
+        .with_base(base.map(ToString::to_string))
+        .changed_files()
+}
+
+/// Path of `root` relative to the top of the git repository containing it, empty if the root is
+/// the top of the repository.
+pub fn root_in_repository(root: &Path) -> anyhow::Result<PathBuf> {
+    let source = GitChangeSource::new(root);
+    source.root_in_repo(&source.open()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::Fixture;
+
+    fn changed(source: GitChangeSource) -> Vec<PathBuf> {
+        let mut files = source.changed_files().unwrap();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn root_commit_is_diffed_against_an_empty_tree() {
+        let fixture = Fixture::workspace(&[("crates/a", &[])]);
+        assert_eq!(
+            changed(GitChangeSource::new(&fixture.root)),
+            [
+                Path::new("Cargo.toml"),
+                Path::new("crates/a/Cargo.toml"),
+                Path::new("crates/a/src/lib.rs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn extra_extensions_are_considered() {
+        let fixture = Fixture::workspace(&[("crates/a", &[])]);
+        fixture.write("crates/a/schema.proto", "syntax = \"proto3\";\n");
+        fixture.commit("Add a proto file");
+
+        assert!(changed(GitChangeSource::new(&fixture.root)).is_empty());
+
+        let mut filter = FileFilter::default();
+        filter.extend(["proto"]);
+        assert_eq!(
+            changed(GitChangeSource::new(&fixture.root).with_filter(filter)),
+            [Path::new("crates/a/schema.proto")]
+        );
+    }
+
+    #[test]
+    fn globs_pick_the_considered_files() {
+        let fixture = Fixture::workspace(&[("crates/a", &[])]);
+        fixture.write("crates/a/docs/example.rs", "fn main() {}\n");
+        fixture.write("crates/a/schema.graphql", "type Query { a: Int }\n");
+        fixture.write("crates/a/docs/schema.graphql", "type Query { a: Int }\n");
+        fixture.commit("Add docs and a schema");
+
+        let mut filter = FileFilter::default();
+        filter
+            .set_globs(&["**/*.graphql".to_string()], &["**/docs/**".to_string()])
+            .unwrap();
+        let root = &fixture.root;
+        // Excluded even though `.rs` files are considered by default
+        assert!(!filter.is_considered_in(root, Path::new("crates/a/docs/example.rs")));
+        // Included even though `.graphql` isn't one of the extensions
+        assert!(filter.is_considered_in(root, Path::new("crates/a/schema.graphql")));
+        // Excludes win over includes
+        assert!(!filter.is_considered_in(root, Path::new("crates/a/docs/schema.graphql")));
+
+        assert_eq!(
+            changed(GitChangeSource::new(root).with_filter(filter)),
+            [Path::new("crates/a/schema.graphql")]
+        );
+    }
+
+    #[test]
+    fn merge_commits_include_changes_from_every_side() {
+        let fixture =
+            Fixture::workspace(&[("crates/a", &[]), ("crates/b", &[]), ("crates/c", &[])]);
+        let base = fixture.head().unwrap();
+        fixture.touch("crates/a/src/lib.rs");
+        fixture.commit("Change a");
+
+        // Make the other side of the merge from the base, only changing b
+        fixture.write("crates/a/src/lib.rs", "");
+        fixture.touch("crates/b/src/lib.rs");
+        let other = fixture.commit_on("Change b", base);
+
+        fixture.touch("crates/a/src/lib.rs");
+        fixture.merge("Merge b", other);
+
+        let expected = [
+            Path::new("crates/a/src/lib.rs"),
+            Path::new("crates/b/src/lib.rs"),
+        ];
+        assert_eq!(changed(GitChangeSource::new(&fixture.root)), expected);
+        assert_eq!(
+            changed(GitChangeSource::new(&fixture.root).with_all_parents(true)),
+            expected
+        );
+    }
 }